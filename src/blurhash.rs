@@ -0,0 +1,103 @@
+//! BlurHash encoder, see https://github.com/woltapp/blurhash/blob/master/Algorithm.md
+
+use image::RgbaImage;
+use std::f32::consts::PI;
+
+const BASE83_CHARACTERS: &[u8] = b"0123456789ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz#$%*+,-.:;=?@[]^_{|}~";
+
+/// Encodes the image with `x_components` * `y_components` components, both in [1, 9]
+pub fn encode(image: &RgbaImage, x_components: u32, y_components: u32) -> String {
+    let (width, height) = image.dimensions();
+    let mut factors: Vec<[f32; 3]> = Vec::with_capacity((x_components * y_components) as usize);
+    for j in 0..y_components {
+        for i in 0..x_components {
+            let normalisation = if i == 0 && j == 0 { 1.0 } else { 2.0 };
+            let mut factor = [0f32; 3];
+            for (x, y, pixel) in image.enumerate_pixels() {
+                let basis = (PI * i as f32 * x as f32 / width as f32).cos() * (PI * j as f32 * y as f32 / height as f32).cos();
+                factor[0] += basis * srgb_to_linear(pixel.0[0]);
+                factor[1] += basis * srgb_to_linear(pixel.0[1]);
+                factor[2] += basis * srgb_to_linear(pixel.0[2]);
+            }
+            let scale = normalisation / (width * height) as f32;
+            factors.push([factor[0] * scale, factor[1] * scale, factor[2] * scale]);
+        }
+    }
+
+    let mut hash = String::new();
+    encode_base83((x_components - 1) + (y_components - 1) * 9, 1, &mut hash);
+
+    let dc = factors[0];
+    let ac = &factors[1..];
+    let max_value = if ac.is_empty() {
+        encode_base83(0, 1, &mut hash);
+        1.0
+    } else {
+        let actual_max = ac.iter().flat_map(|factor| factor.iter()).fold(0f32, |max, value| max.max(value.abs()));
+        let quantised_max = (actual_max * 166.0 - 0.5).floor().clamp(0.0, 82.0) as u32;
+        encode_base83(quantised_max, 1, &mut hash);
+        (quantised_max + 1) as f32 / 166.0
+    };
+
+    encode_base83((linear_to_srgb(dc[0]) << 16) + (linear_to_srgb(dc[1]) << 8) + linear_to_srgb(dc[2]), 4, &mut hash);
+    for factor in ac {
+        let quantise = |value: f32| (sign_pow(value / max_value, 0.5) * 9.0 + 9.5).floor().clamp(0.0, 18.0) as u32;
+        encode_base83(quantise(factor[0]) * 19 * 19 + quantise(factor[1]) * 19 + quantise(factor[2]), 2, &mut hash);
+    }
+    hash
+}
+
+fn encode_base83(value: u32, length: u32, hash: &mut String) {
+    for i in 1..=length {
+        let digit = (value / 83u32.pow(length - i)) % 83;
+        hash.push(BASE83_CHARACTERS[digit as usize] as char);
+    }
+}
+
+fn srgb_to_linear(value: u8) -> f32 {
+    let value = value as f32 / 255.0;
+    if value <= 0.04045 {
+        value / 12.92
+    } else {
+        ((value + 0.055) / 1.055).powf(2.4)
+    }
+}
+
+fn linear_to_srgb(value: f32) -> u32 {
+    let value = value.clamp(0.0, 1.0);
+    if value <= 0.003_130_8 {
+        (value * 12.92 * 255.0 + 0.5) as u32
+    } else {
+        ((1.055 * value.powf(1.0 / 2.4) - 0.055) * 255.0 + 0.5) as u32
+    }
+}
+
+fn sign_pow(value: f32, exponent: f32) -> f32 {
+    value.abs().powf(exponent).copysign(value)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_encode_solid_color() {
+        let image = RgbaImage::from_pixel(32, 24, image::Rgba([255, 0, 0, 255]));
+        let hash = encode(&image, 4, 3);
+        // size flag, then the average color after the quantised maximum AC value
+        let mut average_color = String::new();
+        encode_base83(0xFF0000, 4, &mut average_color);
+        assert_eq!(&hash[..1], "L");
+        assert_eq!(&hash[2..6], average_color);
+        assert_eq!(hash.len(), 4 + 2 * 11 + 2);
+    }
+
+    #[test]
+    fn test_encode_gradient() {
+        let image = RgbaImage::from_fn(32, 32, |x, _| image::Rgba([(x * 8) as u8, 128, 255 - (x * 8) as u8, 255]));
+        let hash = encode(&image, 4, 3);
+        assert_eq!(hash.len(), 4 + 2 * 11 + 2);
+        // the horizontal gradient shows up in the first AC component
+        assert_ne!(&hash[6..8], &hash[8..10]);
+    }
+}
@@ -0,0 +1,102 @@
+//! Least recently used cache bounded by the total size of its values in bytes
+
+use std::collections::{BTreeMap, HashMap};
+use std::hash::Hash;
+
+pub struct LruCache<K, V> {
+    capacity: usize,
+    size: usize,
+    // incremented on every use, the smallest one is the least recently used
+    clock: u64,
+    entries: HashMap<K, (V, u64)>,
+    order: BTreeMap<u64, K>,
+}
+
+impl<K: Hash + Eq + Clone, V: AsRef<[u8]>> LruCache<K, V> {
+    pub fn new(capacity: usize) -> LruCache<K, V> {
+        LruCache {
+            capacity,
+            size: 0,
+            clock: 0,
+            entries: HashMap::new(),
+            order: BTreeMap::new(),
+        }
+    }
+
+    /// Marks the entry as the most recently used one
+    pub fn get(&mut self, key: &K) -> Option<&V> {
+        let (_, used_at) = self.entries.get_mut(key)?;
+        self.order.remove(used_at);
+        self.clock += 1;
+        *used_at = self.clock;
+        self.order.insert(self.clock, key.clone());
+        self.entries.get(key).map(|(value, _)| value)
+    }
+
+    /// Evicts the least recently used entries until it fits, values larger than the capacity are not kept at all
+    pub fn insert(&mut self, key: K, value: V) {
+        self.remove(&key);
+        let length = value.as_ref().len();
+        if length > self.capacity {
+            return;
+        }
+        while self.size + length > self.capacity {
+            let (_, least_recently_used) = match self.order.iter().next() {
+                Some((used_at, key)) => (*used_at, key.clone()),
+                None => break,
+            };
+            self.remove(&least_recently_used);
+        }
+        self.clock += 1;
+        self.size += length;
+        self.order.insert(self.clock, key.clone());
+        self.entries.insert(key, (value, self.clock));
+    }
+
+    pub fn remove(&mut self, key: &K) {
+        if let Some((value, used_at)) = self.entries.remove(key) {
+            self.order.remove(&used_at);
+            self.size -= value.as_ref().len();
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    /// Total size of the values in bytes
+    pub fn size(&self) -> usize {
+        self.size
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_lru_cache() {
+        let mut cache = LruCache::new(10);
+        cache.insert("a", vec![0; 4]);
+        cache.insert("b", vec![1; 4]);
+        assert_eq!((cache.len(), cache.size()), (2, 8));
+
+        // `a` is used more recently than `b` now, so `b` goes first
+        assert_eq!(cache.get(&"a"), Some(&vec![0u8; 4]));
+        cache.insert("c", vec![2; 4]);
+        assert_eq!(cache.get(&"b"), None);
+        assert!(cache.get(&"a").is_some() && cache.get(&"c").is_some());
+        assert_eq!((cache.len(), cache.size()), (2, 8));
+
+        // replacing an entry frees its old value first
+        cache.insert("c", vec![3; 6]);
+        assert_eq!((cache.len(), cache.size()), (2, 10));
+        cache.insert("too large", vec![4; 11]);
+        assert_eq!(cache.get(&"too large"), None);
+        assert_eq!(cache.len(), 2);
+
+        cache.remove(&"a");
+        cache.remove(&"a");
+        assert_eq!((cache.len(), cache.size()), (1, 6));
+    }
+}
@@ -2,16 +2,25 @@ extern crate libc;
 extern crate getopts;
 extern crate serde;
 
-use crossbeam_channel::tick;
+mod blurhash;
+mod lru;
+mod metrics;
+mod palette;
+mod signature;
+mod smartcrop;
+mod storage;
+
 use getopts::Options;
-use glob::glob;
+use hyper::body::Bytes;
+use hyper::server::conn::AddrIncoming;
 use hyper::service::{make_service_fn, service_fn};
 use hyper::{Body, Request, Response, Server, StatusCode};
-use image;
-use libc::{size_t, c_int, c_uchar};
-use num_cpus;
-use serde::Deserialize;
+use image::{self, GenericImageView};
+use libc::{size_t, c_int, c_uchar, c_void};
+use metrics::Metrics;
+use serde::{Deserialize, Serialize};
 use std::cmp::{max, min};
+use std::collections::HashMap;
 use std::io;
 use std::io::prelude::*;
 use std::io::BufReader;
@@ -19,15 +28,20 @@ use std::net::SocketAddr;
 use std::path::{Path, PathBuf};
 use std::ptr::null_mut;
 use std::string::String;
+use std::sync::{Arc, RwLock};
 use std::time::{Duration, SystemTime};
-use threadpool::ThreadPool;
+use storage::{read_body, write_atomically_in_background, FilesystemStorage, HotCache, S3Config, S3Storage, Storage};
 use tokio::fs;
+use tokio::sync::oneshot;
 use walkdir::WalkDir;
 
 macro_rules! generate_http_response_builder {
     ($status_code:expr, $body:expr) => {{
         Response::builder().status($status_code).body($body.into()).unwrap()
     }};
+    ($status_code:expr, $body:expr, $content_type:expr) => {{
+        Response::builder().status($status_code).header("Content-Type", $content_type).body($body.into()).unwrap()
+    }};
 }
 
 macro_rules! generate_http_response {
@@ -40,23 +54,54 @@ macro_rules! generate_http_response {
 
 generate_http_response!(not_found, StatusCode::NOT_FOUND, "Not Found");
 generate_http_response!(method_not_allowed, StatusCode::METHOD_NOT_ALLOWED, "Method Not Allowed");
+generate_http_response!(internal_server_error, StatusCode::INTERNAL_SERVER_ERROR, "Internal Server Error");
+generate_http_response!(bad_gateway, StatusCode::BAD_GATEWAY, "Bad Gateway");
 
 macro_rules! sendfile {
     ($filename:expr) => {{
         match fs::read($filename).await {
-            Ok(buffer) => generate_http_response_builder!(StatusCode::OK, buffer),
+            Ok(buffer) => {
+                let content_type = content_type_of(&buffer);
+                generate_http_response_builder!(StatusCode::OK, buffer, content_type)
+            },
             Err(_) => not_found(),
         }
     }};
 }
 
+/// Content type of original images, guessed from their content
+fn content_type_of(data: &[u8]) -> &'static str {
+    match image::guess_format(data) {
+        Ok(image::ImageFormat::WebP) => "image/webp",
+        Ok(image::ImageFormat::Png) => "image/png",
+        Ok(image::ImageFormat::Jpeg) => "image/jpeg",
+        Ok(image::ImageFormat::Gif) => "image/gif",
+        Ok(image::ImageFormat::Bmp) => "image/bmp",
+        Ok(image::ImageFormat::Ico) => "image/x-icon",
+        Ok(image::ImageFormat::Tiff) => "image/tiff",
+        _ => "application/octet-stream",
+    }
+}
+
+/// Adds `headers` to the `Vary` of the response, keeping the ones already there
+fn append_vary(response: &mut Response<Body>, headers: &str) {
+    let vary = match response.headers().get(hyper::header::VARY).and_then(|vary| vary.to_str().ok()) {
+        Some(vary) => format!("{}, {}", vary, headers),
+        None => headers.to_string(),
+    };
+    response.headers_mut().insert(hyper::header::VARY, hyper::header::HeaderValue::from_str(&vary).unwrap());
+}
+
+#[allow(clippy::duplicated_attributes)]
 #[link(name = "webp", kind = "static")]
+#[link(name = "sharpyuv", kind = "static")]
 #[link(name = "webpwrapper", kind = "static")]
-extern {
+extern "C" {
     fn new_webpwrapper_config() -> *const c_uchar;
     fn drop_webpwrapper_config(config: *const c_uchar);
 
     fn set_webp_config_preset(config: *const c_uchar, value: i32, quality_factor: f32);
+    fn set_webp_config_lossless_preset(config: *const c_uchar, level: i32) -> c_int;
     fn set_webp_config_alpha_compression(config: *const c_uchar, value: i32);
     fn set_webp_config_alpha_filtering(config: *const c_uchar, value: i32);
     fn set_webp_config_alpha_quality(config: *const c_uchar, value: i32);
@@ -83,18 +128,143 @@ extern {
     fn set_webp_config_thread_level(config: *const c_uchar, value: i32);
     fn set_webp_config_use_delta_palette(config: *const c_uchar, value: i32);
     fn set_webp_config_use_sharp_yuv(config: *const c_uchar, value: i32);
+    fn set_webp_config_qmin(config: *const c_uchar, value: i32);
+    fn set_webp_config_qmax(config: *const c_uchar, value: i32);
+    fn webp_validate_config(config: *const c_uchar) -> c_int;
 
     fn webp_encoder(rgba: *const u8, width: c_int, height: c_int, stride: c_int,
                     importer: c_int,
                     config: *const c_uchar,
-                    output: &*mut c_uchar
-    ) -> size_t;
+                    output: &mut *mut u8,
+                    output_size: &mut size_t
+    ) -> c_int;
+    fn webp_decoder(data: *const u8, data_size: size_t, width: &mut c_int, height: &mut c_int) -> *mut u8;
+    fn webp_free(ptr: *mut c_void);
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum WebPImporter {
+    Rgb = 1,
+    Rgba = 2,
+    Bgr = 3,
+    Bgra = 4,
+}
+
+/// Error code of a failed encoding, values above 0 are `WebPEncodingError` from libwebp,
+/// values below 0 are from webpwrapper
+#[derive(Debug, Clone, Copy, PartialEq)]
+struct WebPEncodeError {
+    error_code: i32,
+}
+
+impl WebPEncodeError {
+    const INVALID_IMPORTER: i32 = -1;
+    const PICTURE_INIT: i32 = -2;
+    const BUFFER_TOO_SMALL: i32 = -3;
+    const LOSSLESS_PRESET: i32 = -4;
+
+    fn description(&self) -> &'static str {
+        match self.error_code {
+            1 => "out of memory",
+            2 => "out of memory while flushing bits",
+            3 => "a pointer parameter is NULL",
+            4 => "invalid configuration",
+            5 => "picture has invalid width/height",
+            6 => "partition is bigger than 512k",
+            7 => "partition is bigger than 16M",
+            8 => "error while flushing bytes",
+            9 => "file is bigger than 4G",
+            10 => "aborted by user",
+            WebPEncodeError::INVALID_IMPORTER => "unknown pixel importer",
+            WebPEncodeError::PICTURE_INIT => "cannot initialize picture, version mismatch",
+            WebPEncodeError::BUFFER_TOO_SMALL => "pixel buffer is smaller than stride * height",
+            WebPEncodeError::LOSSLESS_PRESET => "lossless_level is out of range [0, 9]",
+            _ => "unknown error",
+        }
+    }
+}
+
+impl std::fmt::Display for WebPEncodeError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "libwebp failed to encode image: {} (error code {})", self.description(), self.error_code)
+    }
+}
+
+impl std::error::Error for WebPEncodeError {}
+
+impl From<WebPEncodeError> for io::Error {
+    fn from(error: WebPEncodeError) -> io::Error {
+        io::Error::other(error)
+    }
+}
+
+/// Encoded WebP data allocated by libwebp, freed with `WebPFree` on drop
+struct WebPData {
+    ptr: *mut u8,
+    size: usize,
+}
+
+impl std::ops::Deref for WebPData {
+    type Target = [u8];
+
+    fn deref(&self) -> &[u8] {
+        unsafe { std::slice::from_raw_parts(self.ptr, self.size) }
+    }
+}
+
+impl Drop for WebPData {
+    fn drop(&mut self) {
+        unsafe { webp_free(self.ptr as *mut c_void); };
+    }
+}
+
+// the buffer is owned exclusively and never mutated
+unsafe impl Send for WebPData {}
+unsafe impl Sync for WebPData {}
+
+/// Owns a libwebp `WebPConfig` built from a `DirectoryLevelConfig`
+struct WebPEncoder {
+    config: *const c_uchar,
+}
+
+impl WebPEncoder {
+    fn new(config: &DirectoryLevelConfig) -> Result<WebPEncoder, WebPEncodeError> {
+        Ok(WebPEncoder { config: config.to_c_config_ptr()? })
+    }
+
+    fn validate(&self) -> bool {
+        unsafe { webp_validate_config(self.config) != 0 }
+    }
+
+    fn encode(&self, pixels: &[u8], width: u32, height: u32, stride: u32, importer: WebPImporter) -> Result<WebPData, WebPEncodeError> {
+        if (stride as usize) * (height as usize) > pixels.len() || width > i32::MAX as u32 || height > i32::MAX as u32 || stride > i32::MAX as u32 {
+            return Err(WebPEncodeError { error_code: WebPEncodeError::BUFFER_TOO_SMALL });
+        }
+
+        let mut output: *mut u8 = null_mut();
+        let mut output_size: size_t = 0;
+        let error_code = unsafe {
+            webp_encoder(pixels.as_ptr(), width as c_int, height as c_int, stride as c_int,
+                         importer as c_int, self.config, &mut output, &mut output_size)
+        };
+        if error_code != 0 || output.is_null() {
+            return Err(WebPEncodeError { error_code });
+        }
+        Ok(WebPData { ptr: output, size: output_size })
+    }
+}
+
+impl Drop for WebPEncoder {
+    fn drop(&mut self) {
+        unsafe { drop_webpwrapper_config(self.config); };
+    }
 }
 
 const fn config_default_3333u16() -> u16 { 3333 }
 fn config_default_127_0_0_1() -> String { "127.0.0.1".to_string() }
 
 #[derive(Deserialize, Debug, Clone)]
+#[serde(deny_unknown_fields)]
 struct WebPServerConfig {
     #[serde(default = "config_default_127_0_0_1")]
     host: String,
@@ -102,11 +272,440 @@ struct WebPServerConfig {
     port: u16,
     img_path: String,
     webp_path: String,
-    global_config: DirectoryLevelConfig
+    global_config: DirectoryLevelConfig,
+    // opt-in, sizes images by the Client Hints sent by browsers
+    #[serde(default)]
+    client_hints: Option<ClientHintsConfig>,
+    // named query strings of transforms, requested with `?variant=name`
+    #[serde(default)]
+    variants: Option<HashMap<String, String>>,
+    // if set, transforms in the query need a valid `signature`, variants are always allowed
+    #[serde(default)]
+    signing_key: Option<String>,
+    // without `signing_key`, widths and heights in the query are rounded up to one of these
+    #[serde(default)]
+    transform_sizes: Option<Vec<u32>>,
+    // if set, images are fetched from this HTTP service into `img_path` before they are served
+    #[serde(default)]
+    origin: Option<OriginConfig>,
+    // if set, the cache is stored in this bucket instead of `webp_path`
+    #[serde(default)]
+    s3: Option<S3Config>,
+    // opt-in, keeps the most recently served files of the cache in memory
+    #[serde(default)]
+    hot_cache: Option<HotCacheConfig>,
+    // opt-in, serves Prometheus metrics at `/metrics` on its own address
+    #[serde(default)]
+    metrics: Option<MetricsConfig>,
+}
+
+#[derive(Deserialize, Debug, Clone)]
+#[serde(deny_unknown_fields)]
+struct MetricsConfig {
+    #[serde(default = "config_default_127_0_0_1")]
+    host: String,
+    port: u16,
+}
+
+impl MetricsConfig {
+    fn listen_addr(&self) -> Result<SocketAddr, std::net::AddrParseError> {
+        format!("{}:{}", self.host, self.port).parse()
+    }
+}
+
+#[derive(Deserialize, Debug, Clone)]
+#[serde(deny_unknown_fields)]
+struct HotCacheConfig {
+    // total size of the files kept in memory
+    max_bytes: usize,
+}
+
+/// Client Hints requested from browsers with `Accept-CH`, and varied on with `Vary`
+const CLIENT_HINTS: &str = "Sec-CH-DPR, Sec-CH-Width, Sec-CH-Viewport-Width";
+
+#[derive(Deserialize, Debug, Clone)]
+#[serde(deny_unknown_fields)]
+struct ClientHintsConfig {
+    // widths that requested widths are rounded up to, so that only a handful of sizes get cached
+    breakpoints: Vec<u32>,
+}
+
+impl ClientHintsConfig {
+    fn validate(&self) -> Result<(), String> {
+        if self.breakpoints.is_empty() || self.breakpoints.iter().any(|breakpoint| *breakpoint == 0 || *breakpoint > TRANSFORM_MAX_DIMENSION) {
+            return Err(format!("client_hints: breakpoints must be a non-empty list of widths in [1, {}]", TRANSFORM_MAX_DIMENSION));
+        }
+        Ok(())
+    }
+
+    /// Width in physical pixels from `Sec-CH-Width`, or from `Sec-CH-Viewport-Width` and `Sec-CH-DPR`,
+    /// rounded up to the next breakpoint. Wider images get the largest breakpoint
+    fn target_width(&self, headers: &hyper::HeaderMap) -> Option<u32> {
+        let hint = |name: &str| headers.get(name)
+            .and_then(|value| value.to_str().ok())
+            .and_then(|value| value.trim().parse::<f32>().ok())
+            .filter(|value| value.is_finite() && *value > 0.0);
+        let width = match hint("sec-ch-width") {
+            Some(width) => width,
+            None => hint("sec-ch-viewport-width")? * hint("sec-ch-dpr").unwrap_or(1.0),
+        };
+        Some(round_up_to_size(width.ceil().min(u32::MAX as f32) as u32, &self.breakpoints))
+    }
+}
+
+/// The smallest of `sizes` that is at least `value`, or the largest one
+fn round_up_to_size(value: u32, sizes: &[u32]) -> u32 {
+    sizes.iter().copied().filter(|size| *size >= value).min()
+        .or_else(|| sizes.iter().copied().max())
+        .unwrap_or(value)
 }
 
 #[derive(Deserialize, Debug, Clone)]
+#[serde(deny_unknown_fields)]
+struct OriginConfig {
+    // the request path is appended to it, e.g. `http://images.internal/static`
+    url: String,
+    // seconds a fetched image is served without revalidating it, default is 0
+    max_age: Option<u64>,
+    // seconds to wait for the whole response, default is 10
+    timeout: Option<u64>,
+    // largest image that is fetched, default is 64 MiB
+    max_bytes: Option<u64>,
+}
+
+const ORIGIN_DEFAULT_TIMEOUT: u64 = 10;
+const ORIGIN_DEFAULT_MAX_BYTES: u64 = 64 << 20;
+
+/// Validators of a fetched image, stored in the `.origin` sidecar file next to it.
+/// The modification time of the sidecar file is when the image was last validated
+#[derive(Deserialize, Serialize, Debug, Default, PartialEq)]
+struct OriginValidators {
+    etag: Option<String>,
+    last_modified: Option<String>,
+}
+
+impl OriginConfig {
+    fn validate(&self) -> Result<(), String> {
+        match self.url.parse::<hyper::Uri>() {
+            Ok(uri) if uri.scheme_str() == Some("http") && uri.host().is_some() => (),
+            _ => return Err(format!("origin: url {} must be an http:// URL", self.url)),
+        }
+        if self.timeout == Some(0) || self.max_bytes == Some(0) {
+            return Err("origin: timeout and max_bytes must be positive".to_string());
+        }
+        Ok(())
+    }
+
+    /// Makes sure `img_absolute_path` is a fresh copy of the image at the origin, or removes it if the
+    /// origin doesn't have it anymore. A stale copy is kept if the origin cannot be reached
+    async fn fetch(&self, client: &HttpClient, img_uri_path: &str, img_absolute_path: &Path) -> Result<(), io::Error> {
+        // sidecar files are not images, and would be removed as such if the origin doesn't have them
+        if img_uri_path.split('/').any(|segment| segment == "..") || is_origin_sidecar(Path::new(img_uri_path)) {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, format!("invalid path {}", img_uri_path)));
+        }
+        let mut sidecar_path = img_absolute_path.as_os_str().to_owned();
+        sidecar_path.push(".origin");
+        let sidecar_path = PathBuf::from(sidecar_path);
+
+        let cached = fs::metadata(img_absolute_path).await.is_ok_and(|metadata| metadata.is_file());
+        let validators: OriginValidators = match fs::read(&sidecar_path).await {
+            Ok(data) if cached => serde_json::from_slice(&data).unwrap_or_default(),
+            _ => OriginValidators::default(),
+        };
+        if cached {
+            let validated_at = fs::metadata(&sidecar_path).await.and_then(|metadata| metadata.modified()).ok();
+            let age = validated_at.and_then(|validated_at| SystemTime::now().duration_since(validated_at).ok());
+            if age.is_some_and(|age| age.as_secs() < self.max_age.unwrap_or(0)) {
+                return Ok(());
+            }
+        }
+
+        let mut request = Request::get(format!("{}{}", self.url.trim_end_matches('/'), img_uri_path));
+        if let Some(etag) = &validators.etag {
+            request = request.header(hyper::header::IF_NONE_MATCH, etag.as_str());
+        }
+        if let Some(last_modified) = &validators.last_modified {
+            request = request.header(hyper::header::IF_MODIFIED_SINCE, last_modified.as_str());
+        }
+        let request = request.body(Body::empty()).map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?;
+        let exchange = async {
+            let response = client.request(request).await.map_err(io::Error::other)?;
+            let (parts, body) = response.into_parts();
+            let body = match parts.status {
+                StatusCode::OK => read_body(body, self.max_bytes.unwrap_or(ORIGIN_DEFAULT_MAX_BYTES)).await?,
+                _ => Vec::new(),
+            };
+            Ok((parts, body))
+        };
+        let timeout = Duration::from_secs(self.timeout.unwrap_or(ORIGIN_DEFAULT_TIMEOUT));
+        let exchange = tokio::time::timeout(timeout, exchange).await
+            .unwrap_or_else(|_| Err(io::Error::new(io::ErrorKind::TimedOut, format!("no response within {} seconds", timeout.as_secs()))));
+        let (response, body) = match exchange {
+            Ok(exchange) => exchange,
+            Err(e) if cached => {
+                eprintln!("[WARN] Cannot revalidate {} with origin, serving the cached copy: {}", img_uri_path, e);
+                return Ok(());
+            },
+            Err(e) => return Err(e),
+        };
+
+        match response.status {
+            StatusCode::NOT_MODIFIED if cached => write_atomically_in_background(&sidecar_path, serde_json::to_vec(&validators)?).await,
+            StatusCode::OK => {
+                let header = |name| response.headers.get(name).and_then(|value: &hyper::header::HeaderValue| value.to_str().ok()).map(String::from);
+                let validators = OriginValidators { etag: header(hyper::header::ETAG), last_modified: header(hyper::header::LAST_MODIFIED) };
+                if let Some(parent) = img_absolute_path.parent() {
+                    fs::create_dir_all(parent).await?;
+                }
+                write_atomically_in_background(img_absolute_path, body).await?;
+                write_atomically_in_background(&sidecar_path, serde_json::to_vec(&validators)?).await
+            },
+            StatusCode::NOT_FOUND | StatusCode::GONE => {
+                let _ = fs::remove_file(img_absolute_path).await;
+                let _ = fs::remove_file(&sidecar_path).await;
+                Ok(())
+            },
+            _ if cached => {
+                eprintln!("[WARN] Origin responded {} for {}, serving the cached copy", response.status, img_uri_path);
+                Ok(())
+            },
+            status => Err(io::Error::other(format!("origin responded {} for {}", status, img_uri_path))),
+        }
+    }
+}
+
+type HttpClient = hyper::Client<hyper::client::HttpConnector>;
+
+/// Whether it's the `.origin` sidecar file of a fetched image
+fn is_origin_sidecar(path: &Path) -> bool {
+    path.extension().is_some_and(|extension| extension == "origin")
+}
+
+impl WebPServerConfig {
+    /// Where the WebP cache is stored
+    fn storage(&self) -> Arc<dyn Storage> {
+        match &self.s3 {
+            Some(s3) => Arc::new(S3Storage::new(s3.clone(), &self.webp_path)),
+            None => Arc::new(FilesystemStorage),
+        }
+    }
+
+    fn listen_addr(&self) -> Result<SocketAddr, std::net::AddrParseError> {
+        format!("{}:{}", self.host, self.port).parse()
+    }
+
+    fn validate(&self) -> Result<(), String> {
+        self.validate_server()?;
+        if let Err(invalid_fields) = self.global_config.validate() {
+            let messages: Vec<String> = invalid_fields.iter().map(|invalid| format!("global_config: {}", invalid)).collect();
+            return Err(messages.join(", "));
+        }
+        Ok(())
+    }
+
+    // everything except global_config
+    fn validate_server(&self) -> Result<(), String> {
+        if let Err(e) = self.listen_addr() {
+            return Err(format!("invalid listen address {}:{}: {}", self.host, self.port, e));
+        }
+        if !Path::new(&self.img_path).is_dir() {
+            return Err(format!("img_path {} is not a directory", self.img_path));
+        }
+        if self.webp_path.is_empty() {
+            return Err("webp_path cannot be empty".to_string());
+        }
+        if let Some(client_hints) = &self.client_hints {
+            client_hints.validate()?;
+        }
+        if let Some(sizes) = &self.transform_sizes {
+            if sizes.is_empty() || sizes.iter().any(|size| *size == 0 || *size > TRANSFORM_MAX_DIMENSION) {
+                return Err(format!("transform_sizes must be a non-empty list of sizes in [1, {}]", TRANSFORM_MAX_DIMENSION));
+            }
+        }
+        for (name, variant) in self.variants.iter().flatten() {
+            if query_value(variant, "variant").is_some() || query_value(variant, "signature").is_some() {
+                return Err(format!("variant {}: variant and signature cannot be used inside a variant", name));
+            }
+            if let Err(e) = Transform::parse(variant) {
+                return Err(format!("variant {}: {}", name, e));
+            }
+        }
+        if self.signing_key.as_deref() == Some("") {
+            return Err("signing_key cannot be empty".to_string());
+        }
+        if let Some(origin) = &self.origin {
+            origin.validate()?;
+        }
+        if let Some(s3) = &self.s3 {
+            s3.validate()?;
+        }
+        if self.hot_cache.as_ref().is_some_and(|hot_cache| hot_cache.max_bytes == 0) {
+            return Err("hot_cache: max_bytes must be positive".to_string());
+        }
+        if let Some(metrics) = &self.metrics {
+            match metrics.listen_addr() {
+                Err(e) => return Err(format!("metrics: invalid listen address {}:{}: {}", metrics.host, metrics.port, e)),
+                Ok(addr) if Ok(addr) == self.listen_addr() => return Err("metrics: listen address must differ from the one of images".to_string()),
+                Ok(_) => (),
+            }
+        }
+        Ok(())
+    }
+}
+
+#[derive(Debug, Clone)]
+struct PrefetchConfig {
+    enabled: bool,
+    jobs: usize,
+}
+
+/// Application state built once at startup and shared by all connections through an `Arc`.
+struct AppState {
+    config_path: String,
+    prefetch: PrefetchConfig,
+    // for `origin`, kept across reloads so that connections are reused
+    origin_client: HttpClient,
+    // kept across reloads, counters go on from where they were
+    metrics: Arc<Metrics>,
+    // shared by requests and prefetch
+    conversions: Arc<ConversionPool>,
+    // swapped as a whole when reloaded so that every request sees
+    // either the old or the new config, never a mix of both
+    config: RwLock<ConfiguredState>,
+}
+
+/// The config and everything built from it
+struct ConfiguredState {
+    config: Arc<WebPServerConfig>,
+    // through the hot cache if there is one
+    storage: Arc<dyn Storage>,
+    hot_cache: Option<Arc<HotCache>>,
+}
+
+impl ConfiguredState {
+    fn new(config: WebPServerConfig) -> ConfiguredState {
+        let hot_cache = config.hot_cache.as_ref().map(|hot_cache| Arc::new(HotCache::new(config.storage(), hot_cache.max_bytes)));
+        ConfiguredState {
+            storage: match &hot_cache {
+                Some(hot_cache) => Arc::clone(hot_cache) as Arc<dyn Storage>,
+                None => config.storage(),
+            },
+            hot_cache,
+            config: Arc::new(config),
+        }
+    }
+}
+
+impl AppState {
+    fn new(config_path: String, config: WebPServerConfig, prefetch: PrefetchConfig) -> AppState {
+        let metrics = Arc::new(Metrics::new());
+        AppState {
+            config_path,
+            prefetch,
+            origin_client: hyper::Client::new(),
+            conversions: Arc::new(ConversionPool::new(num_cpus::get(), Arc::clone(&metrics))),
+            metrics,
+            config: RwLock::new(ConfiguredState::new(config)),
+        }
+    }
+
+    fn config(&self) -> Arc<WebPServerConfig> {
+        Arc::clone(&self.config.read().unwrap().config)
+    }
+
+    /// The config together with the storage it configures
+    fn current(&self) -> (Arc<WebPServerConfig>, Arc<dyn Storage>) {
+        let current = self.config.read().unwrap();
+        (Arc::clone(&current.config), Arc::clone(&current.storage))
+    }
+
+    fn hot_cache(&self) -> Option<Arc<HotCache>> {
+        self.config.read().unwrap().hot_cache.clone()
+    }
+
+    fn load(&self) -> Result<WebPServerConfig, Box<dyn std::error::Error>> {
+        let config = load_config(&self.config_path)?;
+        config.validate()?;
+        Ok(config)
+    }
+
+    /// The hot cache starts over empty, as the storage behind it may have changed
+    fn replace(&self, config: WebPServerConfig) {
+        if let Some(hot_cache) = self.hot_cache() {
+            let stats = hot_cache.stats();
+            println!("[INFO] Hot cache had {} hits and {} misses, dropping {} files of {} bytes", stats.hits, stats.misses, stats.entries, stats.size);
+        }
+        *self.config.write().unwrap() = ConfiguredState::new(config);
+    }
+}
+
+/// Runs conversions on the blocking threads of the runtime, `jobs` at a time, so that
+/// the runtime goes on with other requests meanwhile
+struct ConversionPool {
+    permits: tokio::sync::Semaphore,
+    // the ones of the app, cache lookups in front of conversions are counted there too
+    metrics: Arc<Metrics>,
+}
+
+impl ConversionPool {
+    fn new(jobs: usize, metrics: Arc<Metrics>) -> ConversionPool {
+        ConversionPool { permits: tokio::sync::Semaphore::new(jobs), metrics }
+    }
+
+    async fn run<Job, T>(&self, job: Job) -> Result<T, io::Error> where
+        Job: 'static + Send + FnOnce() -> Result<T, io::Error>,
+        T: 'static + Send {
+        let _queued = self.metrics.queued_conversion();
+        let _permit = self.permits.acquire().await;
+        let _conversion = self.metrics.conversion();
+        tokio::task::spawn_blocking(job).await.map_err(io::Error::other)?
+    }
+}
+
+#[derive(Debug)]
+struct InvalidField {
+    // empty if the config is rejected as a whole
+    name: &'static str,
+    message: String,
+}
+
+impl std::fmt::Display for InvalidField {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        if self.name.is_empty() {
+            write!(f, "{}", self.message)
+        } else {
+            write!(f, "{}: {}", self.name, self.message)
+        }
+    }
+}
+
+fn preset_type(preset: &str) -> Option<i32> {
+    match preset {
+        "default" => Some(1),
+        "picture" => Some(2),
+        "photo" => Some(3),
+        "drawing" => Some(4),
+        "icon" => Some(5),
+        "text" => Some(6),
+        _ => None,
+    }
+}
+
+fn image_hint_type(hint: &str) -> Option<i32> {
+    match hint {
+        "default" => Some(1),
+        "picture" => Some(2),
+        "photo" => Some(3),
+        "graph" => Some(4),
+        _ => None,
+    }
+}
+
+#[derive(Deserialize, Serialize, Debug, Clone)]
+#[serde(deny_unknown_fields)]
 struct DirectoryLevelConfig {
+    // false to start over from libwebp defaults instead of the parent directory's config
+    inherit: Option<bool>,
     lossless: Option<i32>,
     quality: Option<f32>,
     preset: Option<String>,
@@ -134,11 +733,191 @@ struct DirectoryLevelConfig {
     exact: Option<i32>,
     use_delta_palette: Option<i32>,
     use_sharp_yuv: Option<i32>,
+    qmin: Option<i32>,
+    qmax: Option<i32>,
+    // lossless preset from 0 (fastest) to 9 (slowest, smallest), enables lossless and
+    // sets method and quality, which can still be overridden individually
+    lossless_level: Option<i32>,
+    // ordered dithering when scaling 16-bit images down to 8-bit
+    dithering: Option<i32>,
+    // pick lossless or lossy for each image, `lossless` is ignored if enabled
+    auto_lossless: Option<i32>,
+    // the lossy result is only kept if its PSNR reaches this, in dB
+    auto_lossless_min_psnr: Option<f32>,
+    // search for the lowest quality whose decoded output reaches the SSIM or PSNR target
+    search_target_ssim: Option<f32>,
+    search_target_psnr: Option<f32>,
+    search_min_quality: Option<f32>,
+    search_max_quality: Option<f32>,
+    search_max_iterations: Option<i32>,
+    // wider images are resized down to this width
+    max_width: Option<u32>,
+    // composited onto images after resizing
+    watermark: Option<WatermarkConfig>,
+    // `#rrggbb`, transparent images are flattened onto it
+    background: Option<String>,
+    // encode images without alpha channel if every pixel is opaque anyway
+    drop_opaque_alpha: Option<i32>,
+    // re-encode WebP sources with these parameters, instead of sending them as they are
+    recompress_webp: Option<i32>,
+    // applied in order on top of the parameters above, for each image they match
+    rules: Option<Vec<EncodingRule>>,
+    // applied on top of everything else for requests with `Save-Data: on`
+    save_data: Option<Box<DirectoryLevelConfig>>,
+}
+
+/// Parameters in `config` are used for images that satisfy every condition in `condition`
+#[derive(Deserialize, Serialize, Debug, Clone)]
+#[serde(deny_unknown_fields)]
+struct EncodingRule {
+    #[serde(rename = "match")]
+    condition: RuleCondition,
+    config: DirectoryLevelConfig,
+}
+
+#[derive(Deserialize, Serialize, Debug, Clone)]
+#[serde(deny_unknown_fields)]
+struct RuleCondition {
+    // glob pattern on file name, e.g. `*.png`
+    pattern: Option<String>,
+    // format of the original image detected from its content, e.g. `png`, `jpeg`
+    format: Option<String>,
+    min_width: Option<u32>,
+    max_width: Option<u32>,
+    min_height: Option<u32>,
+    max_height: Option<u32>,
+    has_alpha: Option<bool>,
+}
+
+#[derive(Deserialize, Serialize, Debug, Clone)]
+#[serde(deny_unknown_fields)]
+struct WatermarkConfig {
+    // path of the watermark image, relative paths are relative to the working directory
+    image: String,
+    // top-left, top, top-right, left, center, right, bottom-left, bottom or bottom-right (default)
+    position: Option<String>,
+    // distance to the nearest edges in pixels, default is 16
+    margin: Option<u32>,
+    // in [0, 1], default is 0.5
+    opacity: Option<f32>,
+    // width of the watermark relative to the width of the image in (0, 1], default is 0.2
+    scale: Option<f32>,
+}
+
+const WATERMARK_POSITIONS: [&str; 9] = ["top-left", "top", "top-right", "left", "center", "right", "bottom-left", "bottom", "bottom-right"];
+const WATERMARK_DEFAULT_MARGIN: u32 = 16;
+const WATERMARK_DEFAULT_OPACITY: f32 = 0.5;
+const WATERMARK_DEFAULT_SCALE: f32 = 0.2;
+
+impl WatermarkConfig {
+    fn validate(&self) -> Result<(), String> {
+        if !Path::new(&self.image).is_file() {
+            return Err(format!("watermark image {} is not a file", self.image));
+        }
+        if let Some(position) = &self.position {
+            if !WATERMARK_POSITIONS.contains(&position.as_str()) {
+                return Err(format!("unknown position \"{}\", expected one of {}", position, WATERMARK_POSITIONS.join(", ")));
+            }
+        }
+        if let Some(opacity) = self.opacity {
+            if !(0.0..=1.0).contains(&opacity) {
+                return Err(format!("opacity {} is out of range [0, 1]", opacity));
+            }
+        }
+        if let Some(scale) = self.scale {
+            if !(scale > 0.0 && scale <= 1.0) {
+                return Err(format!("scale {} is out of range (0, 1]", scale));
+            }
+        }
+        Ok(())
+    }
+
+    /// Composites the watermark onto the image with the "over" operator
+    fn apply(&self, image: image::DynamicImage) -> Result<image::DynamicImage, io::Error> {
+        let watermark = match image::open(&self.image) {
+            Ok(watermark) => watermark,
+            Err(e) => return Err(io::Error::new(io::ErrorKind::InvalidData, format!("Cannot decode watermark: {}: {}", self.image, e))),
+        };
+        let (width, height) = image.dimensions();
+        let watermark_width = ((width as f32 * self.scale.unwrap_or(WATERMARK_DEFAULT_SCALE)).round() as u32).max(1);
+        let watermark = watermark.resize(watermark_width, u32::MAX, image::imageops::FilterType::Lanczos3).to_rgba8();
+        let (watermark_width, watermark_height) = watermark.dimensions();
+
+        let margin = self.margin.unwrap_or(WATERMARK_DEFAULT_MARGIN) as i64;
+        let position = self.position.as_deref().unwrap_or("bottom-right");
+        let x = match position {
+            "top-left" | "left" | "bottom-left" => margin,
+            "top" | "center" | "bottom" => (width as i64 - watermark_width as i64) / 2,
+            _ => width as i64 - watermark_width as i64 - margin,
+        };
+        let y = match position {
+            "top-left" | "top" | "top-right" => margin,
+            "left" | "center" | "right" => (height as i64 - watermark_height as i64) / 2,
+            _ => height as i64 - watermark_height as i64 - margin,
+        };
+
+        let opacity = self.opacity.unwrap_or(WATERMARK_DEFAULT_OPACITY);
+        let has_alpha = image.color().has_alpha();
+        let mut canvas = image.to_rgba8();
+        for (watermark_x, watermark_y, source) in watermark.enumerate_pixels() {
+            let (canvas_x, canvas_y) = (x + watermark_x as i64, y + watermark_y as i64);
+            if canvas_x < 0 || canvas_y < 0 || canvas_x >= width as i64 || canvas_y >= height as i64 {
+                continue;
+            }
+            let destination = canvas.get_pixel_mut(canvas_x as u32, canvas_y as u32);
+            let source_alpha = source.0[3] as f32 / 255.0 * opacity;
+            let destination_alpha = destination.0[3] as f32 / 255.0;
+            let alpha = source_alpha + destination_alpha * (1.0 - source_alpha);
+            if alpha <= 0.0 {
+                continue;
+            }
+            for channel in 0..3 {
+                let color = (source.0[channel] as f32 * source_alpha + destination.0[channel] as f32 * destination_alpha * (1.0 - source_alpha)) / alpha;
+                destination.0[channel] = color.round().clamp(0.0, 255.0) as u8;
+            }
+            destination.0[3] = (alpha * 255.0).round() as u8;
+        }
+
+        // keep opaque images opaque, so that they are still imported as RGB
+        Ok(if has_alpha {
+            image::DynamicImage::ImageRgba8(canvas)
+        } else {
+            image::DynamicImage::ImageRgb8(image::DynamicImage::ImageRgba8(canvas).to_rgb8())
+        })
+    }
+}
+
+impl RuleCondition {
+    fn validate(&self) -> Result<(), String> {
+        if let Some(pattern) = &self.pattern {
+            if let Err(e) = glob::Pattern::new(pattern) {
+                return Err(format!("invalid pattern \"{}\": {}", pattern, e));
+            }
+        }
+        if let Some(format) = &self.format {
+            if image::ImageFormat::from_extension(format).is_none() {
+                return Err(format!("unknown format \"{}\"", format));
+            }
+        }
+        Ok(())
+    }
+
+    fn matches(&self, file_name: &str, format: Option<image::ImageFormat>, image: &image::DynamicImage) -> bool {
+        let (width, height) = image.dimensions();
+        self.pattern.as_ref().is_none_or(|pattern| glob::Pattern::new(pattern).is_ok_and(|pattern| pattern.matches(file_name)))
+            && self.format.as_ref().is_none_or(|expected| format.is_some() && image::ImageFormat::from_extension(expected) == format)
+            && self.min_width.is_none_or(|min_width| width >= min_width)
+            && self.max_width.is_none_or(|max_width| width <= max_width)
+            && self.min_height.is_none_or(|min_height| height >= min_height)
+            && self.max_height.is_none_or(|max_height| height <= max_height)
+            && self.has_alpha.is_none_or(|has_alpha| image.color().has_alpha() == has_alpha)
+    }
 }
 
 impl DirectoryLevelConfig {
     const fn new() -> DirectoryLevelConfig {
         DirectoryLevelConfig {
+            inherit: None,
             lossless: None,
             quality: None,
             preset: None,
@@ -165,67 +944,339 @@ impl DirectoryLevelConfig {
             near_lossless: None,
             exact: None,
             use_delta_palette: None,
-            use_sharp_yuv: None
+            use_sharp_yuv: None,
+            qmin: None,
+            qmax: None,
+            lossless_level: None,
+            dithering: None,
+            auto_lossless: None,
+            auto_lossless_min_psnr: None,
+            search_target_ssim: None,
+            search_target_psnr: None,
+            search_min_quality: None,
+            search_max_quality: None,
+            search_max_iterations: None,
+            max_width: None,
+            watermark: None,
+            background: None,
+            drop_opaque_alpha: None,
+            recompress_webp: None,
+            rules: None,
+            save_data: None,
         }
     }
 
-    fn detect(directory_path: &str, global_config: &DirectoryLevelConfig) -> DirectoryLevelConfig {
-        let mut directory_level_config_path = PathBuf::from(directory_path);
-        directory_level_config_path.push(".webp-conf");
-        match std::fs::File::open(directory_level_config_path.as_path()) {
-            Ok(file) => {
-                match serde_json::from_reader(BufReader::new(file)) {
-                    Ok(conf) => conf,
-                    _ => global_config.clone(),
+    /// Returns the effective config for given directory. Starting from the global config, each
+    /// `.webp-conf` from `img_path` down to the directory overrides the parameters it sets, unless
+    /// it has `"inherit": false`, then it starts over from libwebp defaults.
+    /// Errors are formatted as `path:line: message`, or name the files the effective config comes from.
+    fn detect(img_path: &str, directory_path: &str, global_config: &DirectoryLevelConfig) -> Result<DirectoryLevelConfig, String> {
+        // /IMG_PATH, /IMG_PATH/path, /IMG_PATH/path/to
+        let directory_path = Path::new(directory_path);
+        let level_paths: Vec<PathBuf> = match directory_path.strip_prefix(img_path) {
+            Ok(relative_path) => {
+                let mut level_path = PathBuf::from(img_path);
+                let mut level_paths = vec![level_path.clone()];
+                for component in relative_path.components() {
+                    level_path.push(component);
+                    level_paths.push(level_path.clone());
                 }
+                level_paths
             },
-            _ => global_config.clone(),
+            Err(_) => vec![directory_path.to_path_buf()],
+        };
+
+        let mut effective_config = global_config.clone();
+        let mut sources = vec!["global_config".to_string()];
+        for level_path in level_paths {
+            let directory_level_config_path = level_path.join(".webp-conf");
+            if !directory_level_config_path.exists() {
+                continue;
+            }
+            let directory_level_config = DirectoryLevelConfig::load(&directory_level_config_path).map_err(|errors| errors.join("\n"))?;
+            effective_config = if directory_level_config.inherit == Some(false) {
+                sources.clear();
+                directory_level_config
+            } else {
+                effective_config.merge(&directory_level_config)
+            };
+            sources.push(directory_level_config_path.display().to_string());
         }
-    }
+        effective_config.inherit = None;
 
-    fn to_c_config_ptr(&self) -> *const c_uchar {
-        unsafe {
-            let ptr: *const c_uchar = new_webpwrapper_config();
+        // each level is valid on its own, but libwebp may still reject the combination
+        match effective_config.validate() {
+            Ok(()) => Ok(effective_config),
+            Err(invalid_fields) => Err(invalid_fields.iter().map(|invalid| {
+                format!("{}: effective config of {}: {}", directory_path.display(), sources.join(", "), invalid)
+            }).collect::<Vec<String>>().join("\n")),
+        }
+    }
 
-            let preset = match &self.preset {
-                Some(preset) => match &preset[..] {
-                    "picture" => 2,
-                    "photo" => 3,
-                    "drawing" => 4,
-                    "icon" => 5,
-                    "text" => 6,
-                    _ => 1,
-                },
-                _ => 1,
+    /// Parameters set in `overrides` take precedence over the ones in `self`
+    fn merge(&self, overrides: &DirectoryLevelConfig) -> DirectoryLevelConfig {
+        macro_rules! merge_parameters {
+            ($($param:ident),*) => {
+                DirectoryLevelConfig {
+                    $($param: overrides.$param.clone().or_else(|| self.$param.clone()),)*
+                }
             };
-            set_webp_config_preset(ptr, preset, self.quality.unwrap_or(75.0));
+        }
 
-            macro_rules! set_parameter {
-                ($func_name:ident, $config:expr, $config_ptr:expr, $param:ident) => {
-                    if let Some(value) = $config.$param {
-                        $func_name($config_ptr, value);
-                    }
-                };
+        merge_parameters!(inherit, lossless, quality, preset, method, image_hint, target_size, target_psnr,
+                          segments, sns_strength, filter_strength, filter_sharpness, filter_type, autofilter,
+                          alpha_compression, alpha_filtering, alpha_quality, pass, preprocessing, partitions,
+                          partition_limit, emulate_jpeg_size, thread_level, low_memory, near_lossless, exact,
+                          use_delta_palette, use_sharp_yuv, qmin, qmax, lossless_level, dithering, auto_lossless, auto_lossless_min_psnr,
+                          search_target_ssim, search_target_psnr, search_min_quality, search_max_quality,
+                          search_max_iterations, max_width, watermark, background, drop_opaque_alpha,
+                          recompress_webp, rules, save_data)
+    }
+
+    /// Applies every rule that matches given image in order, later ones take precedence
+    fn select(&self, file_name: &str, format: Option<image::ImageFormat>, image: &image::DynamicImage) -> DirectoryLevelConfig {
+        let mut selected_config = self.clone();
+        for rule in self.rules.iter().flatten() {
+            if rule.condition.matches(file_name, format, image) {
+                selected_config = selected_config.merge(&rule.config);
             }
+        }
+        selected_config.rules = None;
+        selected_config
+    }
 
-            set_parameter!(set_webp_config_alpha_compression, self, ptr, alpha_compression);
-            set_parameter!(set_webp_config_alpha_filtering, self, ptr, alpha_filtering);
-            set_parameter!(set_webp_config_alpha_quality, self, ptr, alpha_quality);
+    /// Parameters for requests with `Save-Data: on`
+    fn for_save_data(&self) -> DirectoryLevelConfig {
+        let mut save_data_config = match &self.save_data {
+            Some(save_data) => self.merge(save_data),
+            None => self.clone(),
+        };
+        save_data_config.save_data = None;
+        save_data_config
+    }
+
+    /// Changes whenever any watermark that may be used, or any of their images, changes.
+    /// None if there is no watermark at all
+    fn watermark_key(&self) -> Option<u64> {
+        use std::hash::{Hash, Hasher};
+
+        let watermarks: Vec<&WatermarkConfig> = self.watermark.iter()
+            .chain(self.rules.iter().flatten().filter_map(|rule| rule.config.watermark.as_ref()))
+            .chain(self.save_data.iter().filter_map(|save_data| save_data.watermark.as_ref()))
+            .collect();
+        if watermarks.is_empty() {
+            return None;
+        }
+        let mut hasher = std::collections::hash_map::DefaultHasher::new();
+        for watermark in watermarks {
+            serde_json::to_string(watermark).unwrap_or_default().hash(&mut hasher);
+            std::fs::metadata(&watermark.image).and_then(|metadata| metadata.modified()).ok().hash(&mut hasher);
+        }
+        Some(hasher.finish())
+    }
+
+    fn load(path: &Path) -> Result<DirectoryLevelConfig, Vec<String>> {
+        let source = match std::fs::read_to_string(path) {
+            Ok(source) => source,
+            Err(e) => return Err(vec![format!("{}: {}", path.display(), e)]),
+        };
+        let config: DirectoryLevelConfig = match serde_json::from_str(&source) {
+            Ok(config) => config,
+            Err(e) => return Err(vec![format!("{}:{}: {}", path.display(), e.line(), e)]),
+        };
+        match config.validate() {
+            Ok(()) => Ok(config),
+            Err(invalid_fields) => Err(invalid_fields.iter().map(|invalid| {
+                format!("{}:{}: {}", path.display(), line_of_key(&source, invalid.name).unwrap_or(1), invalid)
+            }).collect()),
+        }
+    }
+
+    /// Checks every field against the ranges accepted by libwebp, then lets libwebp
+    /// validate the combination of them.
+    fn validate(&self) -> Result<(), Vec<InvalidField>> {
+        let mut invalid_fields = Vec::new();
+
+        macro_rules! check_range {
+            ($param:ident, $min:expr, $max:expr) => {
+                if let Some(value) = self.$param {
+                    if !($min..=$max).contains(&value) {
+                        invalid_fields.push(InvalidField {
+                            name: stringify!($param),
+                            message: format!("{} is out of range [{}, {}]", value, $min, $max),
+                        });
+                    }
+                }
+            };
+        }
+
+        check_range!(lossless, 0, 1);
+        check_range!(quality, 0.0, 100.0);
+        check_range!(method, 0, 6);
+        check_range!(target_size, 0, i32::MAX);
+        check_range!(target_psnr, 0.0, f32::MAX);
+        check_range!(segments, 1, 4);
+        check_range!(sns_strength, 0, 100);
+        check_range!(filter_strength, 0, 100);
+        check_range!(filter_sharpness, 0, 7);
+        check_range!(filter_type, 0, 1);
+        check_range!(autofilter, 0, 1);
+        check_range!(alpha_compression, 0, 1);
+        check_range!(alpha_filtering, 0, 2);
+        check_range!(alpha_quality, 0, 100);
+        check_range!(pass, 1, 10);
+        check_range!(preprocessing, 0, 7);
+        check_range!(partitions, 0, 3);
+        check_range!(partition_limit, 0, 100);
+        check_range!(emulate_jpeg_size, 0, 1);
+        check_range!(thread_level, 0, 1);
+        check_range!(low_memory, 0, 1);
+        check_range!(near_lossless, 0, 100);
+        check_range!(exact, 0, 1);
+        check_range!(use_delta_palette, 0, 1);
+        check_range!(use_sharp_yuv, 0, 1);
+        check_range!(qmin, 0, 100);
+        check_range!(qmax, 0, 100);
+        check_range!(lossless_level, 0, 9);
+        check_range!(dithering, 0, 1);
+        check_range!(auto_lossless, 0, 1);
+        check_range!(auto_lossless_min_psnr, 0.0, 99.0);
+        check_range!(search_target_ssim, 0.0, 1.0);
+        check_range!(search_target_psnr, 0.0, 99.0);
+        check_range!(search_min_quality, 0.0, 100.0);
+        check_range!(search_max_quality, 0.0, 100.0);
+        check_range!(search_max_iterations, 1, 20);
+        check_range!(max_width, 1, u32::MAX);
+        check_range!(drop_opaque_alpha, 0, 1);
+        check_range!(recompress_webp, 0, 1);
+
+        if let Some(preset) = &self.preset {
+            if preset_type(preset).is_none() {
+                invalid_fields.push(InvalidField {
+                    name: "preset",
+                    message: format!("unknown preset \"{}\", expected one of default, picture, photo, drawing, icon, text", preset),
+                });
+            }
+        }
+        if let Some(hint) = &self.image_hint {
+            if image_hint_type(hint).is_none() {
+                invalid_fields.push(InvalidField {
+                    name: "image_hint",
+                    message: format!("unknown image hint \"{}\", expected one of default, picture, photo, graph", hint),
+                });
+            }
+        }
+        if self.search_target_ssim.is_some() || self.search_target_psnr.is_some() {
+            if self.search_target_ssim.is_some() && self.search_target_psnr.is_some() {
+                invalid_fields.push(InvalidField { name: "search_target_psnr", message: "cannot be used with search_target_ssim".to_string() });
+            }
+            if self.lossless == Some(1) || self.auto_lossless == Some(1) {
+                invalid_fields.push(InvalidField { name: "search_target_ssim", message: "quality search only works with lossy encoding".to_string() });
+            }
+        }
+        if self.qmin.unwrap_or(0) > self.qmax.unwrap_or(100) {
+            invalid_fields.push(InvalidField { name: "qmin", message: "cannot be greater than qmax".to_string() });
+        }
+        if self.search_min_quality.unwrap_or(SEARCH_DEFAULT_MIN_QUALITY) > self.search_max_quality.unwrap_or(SEARCH_DEFAULT_MAX_QUALITY) {
+            invalid_fields.push(InvalidField { name: "search_min_quality", message: "cannot be greater than search_max_quality".to_string() });
+        }
+        for (index, rule) in self.rules.iter().flatten().enumerate() {
+            if let Err(message) = rule.condition.validate() {
+                invalid_fields.push(InvalidField { name: "rules", message: format!("rule #{}: {}", index + 1, message) });
+            }
+            if rule.config.inherit.is_some() || rule.config.rules.is_some() || rule.config.save_data.is_some() {
+                invalid_fields.push(InvalidField { name: "rules", message: format!("rule #{}: inherit, rules and save_data cannot be used inside a rule", index + 1) });
+                continue;
+            }
+            if rule.config.recompress_webp.is_some() {
+                // whether to decode at all is decided before any rule can be matched
+                invalid_fields.push(InvalidField { name: "rules", message: format!("rule #{}: recompress_webp cannot be used inside a rule", index + 1) });
+            }
+            let mut rule_config = self.merge(&rule.config);
+            rule_config.rules = None;
+            if let Err(rule_invalid_fields) = rule_config.validate() {
+                for invalid in rule_invalid_fields {
+                    invalid_fields.push(InvalidField { name: "rules", message: format!("rule #{}: {}", index + 1, invalid) });
+                }
+            }
+        }
+
+        if let Some(background) = &self.background {
+            if parse_hex_color(background).is_none() {
+                invalid_fields.push(InvalidField { name: "background", message: format!("\"{}\" is not a #rrggbb color", background) });
+            }
+        }
+        if let Some(watermark) = &self.watermark {
+            if let Err(message) = watermark.validate() {
+                invalid_fields.push(InvalidField { name: "watermark", message });
+            }
+        }
+        if let Some(save_data) = &self.save_data {
+            if save_data.inherit.is_some() || save_data.rules.is_some() || save_data.save_data.is_some() {
+                invalid_fields.push(InvalidField { name: "save_data", message: "inherit, rules and save_data cannot be used inside save_data".to_string() });
+            } else {
+                let mut save_data_config = self.for_save_data();
+                save_data_config.rules = None;
+                if let Err(save_data_invalid_fields) = save_data_config.validate() {
+                    for invalid in save_data_invalid_fields {
+                        invalid_fields.push(InvalidField { name: "save_data", message: format!("save_data: {}", invalid) });
+                    }
+                }
+            }
+        }
+        if invalid_fields.is_empty() {
+            match WebPEncoder::new(self) {
+                Ok(encoder) if encoder.validate() => (),
+                Ok(_) => invalid_fields.push(InvalidField {
+                    name: "",
+                    message: "rejected by libwebp WebPValidateConfig".to_string(),
+                }),
+                Err(e) => invalid_fields.push(InvalidField {
+                    name: "lossless_level",
+                    message: e.description().to_string(),
+                }),
+            }
+        }
+
+        if invalid_fields.is_empty() {
+            Ok(())
+        } else {
+            Err(invalid_fields)
+        }
+    }
+
+    /// Fails if libwebp rejects `lossless_level`, the config is freed then
+    fn to_c_config_ptr(&self) -> Result<*const c_uchar, WebPEncodeError> {
+        unsafe {
+            let ptr: *const c_uchar = new_webpwrapper_config();
+
+            let preset = self.preset.as_deref().and_then(preset_type).unwrap_or(1);
+            set_webp_config_preset(ptr, preset, self.quality.unwrap_or(75.0));
+            if let Some(level) = self.lossless_level {
+                if set_webp_config_lossless_preset(ptr, level) == 0 {
+                    drop_webpwrapper_config(ptr);
+                    return Err(WebPEncodeError { error_code: WebPEncodeError::LOSSLESS_PRESET });
+                }
+            }
+
+            macro_rules! set_parameter {
+                ($func_name:ident, $config:expr, $config_ptr:expr, $param:ident) => {
+                    if let Some(value) = $config.$param {
+                        $func_name($config_ptr, value);
+                    }
+                };
+            }
+
+            set_parameter!(set_webp_config_alpha_compression, self, ptr, alpha_compression);
+            set_parameter!(set_webp_config_alpha_filtering, self, ptr, alpha_filtering);
+            set_parameter!(set_webp_config_alpha_quality, self, ptr, alpha_quality);
             set_parameter!(set_webp_config_autofilter, self, ptr, autofilter);
             set_parameter!(set_webp_config_emulate_jpeg_size, self, ptr, emulate_jpeg_size);
             set_parameter!(set_webp_config_exact, self, ptr, exact);
             set_parameter!(set_webp_config_filter_sharpness, self, ptr, filter_sharpness);
             set_parameter!(set_webp_config_filter_strength, self, ptr, filter_strength);
             set_parameter!(set_webp_config_filter_type, self, ptr, filter_type);
-            set_webp_config_image_hint(ptr, match &self.image_hint {
-                Some(hint) => match &hint[..] {
-                    "picture" => 2,
-                    "photo" => 3,
-                    "graph" => 4,
-                    _ => 1
-                },
-                _ => 1,
-            });
+            set_webp_config_image_hint(ptr, self.image_hint.as_deref().and_then(image_hint_type).unwrap_or(1));
             set_parameter!(set_webp_config_lossless, self, ptr, lossless);
             set_parameter!(set_webp_config_low_memory, self, ptr, low_memory);
             set_parameter!(set_webp_config_method, self, ptr, method);
@@ -242,384 +1293,2284 @@ impl DirectoryLevelConfig {
             set_parameter!(set_webp_config_thread_level, self, ptr, thread_level);
             set_parameter!(set_webp_config_use_delta_palette, self, ptr, use_delta_palette);
             set_parameter!(set_webp_config_use_sharp_yuv, self, ptr, use_sharp_yuv);
-            ptr
+            set_parameter!(set_webp_config_qmin, self, ptr, qmin);
+            set_parameter!(set_webp_config_qmax, self, ptr, qmax);
+            Ok(ptr)
+        }
+    }
+}
+
+#[tokio::main]
+async fn main() -> std::result::Result<(), Box<dyn std::error::Error + Send + Sync>> {
+    let state = Arc::new(from_cli_args());
+    prefetch_if_requested(state.config().as_ref().clone(), &state.prefetch, Arc::clone(&state.conversions), true, ||{});
+
+    // bound once, a reloaded config cannot move it
+    if let Some(metrics) = &state.config().metrics {
+        let metrics_addr = metrics.listen_addr()?;
+        let metrics_state = Arc::clone(&state);
+        let metrics_server = Server::try_bind(&metrics_addr)?.serve(make_service_fn(move |_| {
+            let state = Arc::clone(&metrics_state);
+            async move { Ok::<_, hyper::Error>(service_fn(move |req| metrics_services(Arc::clone(&state), req))) }
+        }));
+        println!("Metrics on http://{}/metrics", metrics_addr);
+        tokio::spawn(async move {
+            if let Err(e) = metrics_server.await {
+                eprintln!("[ERROR] Metrics server stopped: {}", e);
+            }
+        });
+    }
+
+    let mut reload_signal = ReloadSignal::new()?;
+    let mut addr = state.config().listen_addr()?;
+    let mut builder = Server::try_bind(&addr)?;
+    loop {
+        let (shutdown_tx, shutdown_rx) = oneshot::channel::<()>();
+        let service_state = Arc::clone(&state);
+        let server = builder.serve(make_service_fn(move |_| {
+            let state = Arc::clone(&service_state);
+            async move { Ok::<_, hyper::Error>(service_fn(move |req| webp_services(Arc::clone(&state), req))) }
+        })).with_graceful_shutdown(async { let _ = shutdown_rx.await; });
+        println!("WebP image service on http://{}", addr);
+        let mut server = tokio::spawn(server);
+
+        // serve until a reloaded config asks for another host or port
+        let (next_addr, next_builder) = loop {
+            tokio::select! {
+                result = &mut server => {
+                    result??;
+                    return Ok(());
+                },
+                _ = reload_signal.recv() => {
+                    if let Some(rebind) = reload(&state, addr) {
+                        break rebind;
+                    }
+                },
+            }
+        };
+
+        // the old server finishes in-flight requests in background
+        let _ = shutdown_tx.send(());
+        addr = next_addr;
+        builder = next_builder;
+    }
+}
+
+/// Re-reads the config file and swaps it in for new requests. The old config is kept
+/// if the new one is invalid or its listen address cannot be bound. Returns the new
+/// listener when host or port has changed.
+fn reload(state: &AppState, addr: SocketAddr) -> Option<(SocketAddr, hyper::server::Builder<AddrIncoming>)> {
+    println!("[INFO] Reloading config file {}", state.config_path);
+    let config = match state.load() {
+        Ok(config) => config,
+        Err(e) => {
+            eprintln!("[ERROR] Cannot reload config file {}: {}, keep using the old one", state.config_path, e);
+            return None;
+        }
+    };
+
+    // already validated
+    let new_addr = config.listen_addr().unwrap();
+    if new_addr == addr {
+        state.replace(config);
+        println!("[INFO] Config reloaded");
+        return None;
+    }
+
+    match Server::try_bind(&new_addr) {
+        Ok(builder) => {
+            state.replace(config);
+            println!("[INFO] Config reloaded, moving from http://{} to http://{}", addr, new_addr);
+            Some((new_addr, builder))
+        },
+        Err(e) => {
+            eprintln!("[ERROR] Cannot bind to {}: {}, keep using the old config", new_addr, e);
+            None
+        }
+    }
+}
+
+#[cfg(unix)]
+struct ReloadSignal(tokio::signal::unix::Signal);
+
+#[cfg(unix)]
+impl ReloadSignal {
+    fn new() -> io::Result<ReloadSignal> {
+        Ok(ReloadSignal(tokio::signal::unix::signal(tokio::signal::unix::SignalKind::hangup())?))
+    }
+
+    async fn recv(&mut self) {
+        if self.0.recv().await.is_none() {
+            std::future::pending::<()>().await
+        }
+    }
+}
+
+// SIGHUP is not available, config can only be changed by restarting
+#[cfg(not(unix))]
+struct ReloadSignal;
+
+#[cfg(not(unix))]
+impl ReloadSignal {
+    fn new() -> io::Result<ReloadSignal> {
+        Ok(ReloadSignal)
+    }
+
+    async fn recv(&mut self) {
+        std::future::pending::<()>().await
+    }
+}
+
+/// Converts every image under `img_path` in background, `jobs` at a time. Runs on the current runtime,
+/// with conversions on its blocking threads, so that the cache is written the same way requests write it
+fn prefetch_if_requested<Callback>(config: WebPServerConfig, prefetch: &PrefetchConfig, conversions: Arc<ConversionPool>, verbose: bool, callback: Callback) where
+    Callback: 'static + Send + FnOnce() {
+    if !prefetch.enabled {
+        return;
+    }
+    let jobs = prefetch.jobs;
+    tokio::spawn(async move {
+        if verbose { println!("[INFO] Prefetch Started"); }
+        let now = SystemTime::now();
+        let img_path = config.img_path.clone();
+        let images = tokio::task::spawn_blocking(move || {
+            WalkDir::new(&img_path).into_iter().filter_map(|e| e.ok())
+                .filter(|e| e.path().is_file() && !is_origin_sidecar(e.path()))
+                .map(|e| e.into_path())
+                .collect::<Vec<PathBuf>>()
+        }).await.unwrap_or_default();
+
+        let filecount = images.len();
+        let storage = config.storage();
+        let config = Arc::new(config);
+        let semaphore = Arc::new(tokio::sync::Semaphore::new(jobs));
+        let done = Arc::new(std::sync::atomic::AtomicUsize::new(0));
+        let mut handles = Vec::with_capacity(filecount);
+        for img_absolute_path in images {
+            let permit = Arc::clone(&semaphore).acquire_owned().await;
+            let (config, storage, conversions, done) = (Arc::clone(&config), Arc::clone(&storage), Arc::clone(&conversions), Arc::clone(&done));
+            handles.push(tokio::spawn(async move {
+                prefetch_image(&config, storage.as_ref(), &conversions, img_absolute_path, verbose).await;
+                drop(permit);
+                let done = done.fetch_add(1, std::sync::atomic::Ordering::Relaxed) + 1;
+                conversions.metrics.prefetch_progress(done, filecount);
+                if verbose {
+                    print!("\r[INFO] Prefetch progress: [{}/{}]", done, filecount);
+                    let _ = std::io::stdout().flush();
+                }
+            }));
+        }
+        for handle in handles {
+            let _ = handle.await;
+        }
+        if verbose { println!("\n[INFO] Prefetch done, elapsed time: {:.4} seconds", now.elapsed().unwrap().as_secs_f32()); }
+        callback();
+    });
+}
+
+/// Converts an image the same way a request without query parameters would, unless it's already in cache
+async fn prefetch_image(config: &WebPServerConfig, storage: &dyn Storage, conversions: &ConversionPool, img_absolute_path: PathBuf, verbose: bool) {
+    let img_uri_path = &img_absolute_path.to_str().unwrap()[config.img_path.len()..];
+    let webp_converted_paths = generate_webp_paths(&img_absolute_path, img_uri_path, &config.webp_path);
+    let webp_img_absolute_path = webp_converted_paths.0;
+    let dir_absolute_path = webp_converted_paths.2.to_str().unwrap();
+
+    let directory_level_config = match DirectoryLevelConfig::detect(&config.img_path, dir_absolute_path, &config.global_config) {
+        Ok(directory_level_config) => directory_level_config,
+        Err(e) => {
+            if verbose { eprintln!("\r[ERROR] Invalid directory-level config, skipped {}\n{}", img_absolute_path.display(), e); }
+            return;
+        }
+    };
+    let transform = Transform { watermark: directory_level_config.watermark_key(), ..Transform::default() };
+    if transform == Transform::default() && directory_level_config.recompress_webp != Some(1) && is_webp_file(&img_absolute_path) {
+        return;
+    }
+    let cache_path = transform.cache_path(&webp_img_absolute_path);
+    if let Ok(None) = storage.stat(&cache_path).await {
+        // try to convert image to webp format
+        if convert(storage, conversions, img_absolute_path.to_str().unwrap(), &cache_path, &directory_level_config, &transform).await.is_ok() {
+            remove_old_cached_webp(storage, &webp_img_absolute_path, &img_absolute_path).await;
+        }
+    }
+}
+
+fn generate_webp_paths(img_absolute_path: &Path, img_uri_path: &str, webp_cache_path: &str) -> (PathBuf, PathBuf, PathBuf) {
+    // aya.jpg
+    let img_name = img_absolute_path.file_name().unwrap().to_str().unwrap();
+    // /path/to
+    let mut dir_uri_path = PathBuf::from(&img_uri_path);
+    dir_uri_path.pop();
+    let dir_uri_path = dir_uri_path.to_str().unwrap();
+    // /IMG_PATH/path/to/
+    let mut dir_absolute_path = PathBuf::from(&img_absolute_path);
+    dir_absolute_path.pop();
+
+    // 1582735380
+    let modified_time = match std::fs::metadata(img_absolute_path) {
+        Ok(metadata) => match metadata.modified() {
+            Ok(modified_time) => modified_time.duration_since(SystemTime::UNIX_EPOCH).unwrap().as_secs(),
+            Err(e) => {
+                eprintln!("{}", e);
+                0
+            }
+        },
+        Err(e) => {
+            eprintln!("{}", e);
+            0
+        }
+    };
+
+    // aya.jpg.1582735380.webp
+    let mut webp_img_name = String::from(img_name);
+    webp_img_name.push('.');
+    webp_img_name.push_str(&modified_time.to_string());
+    webp_img_name.push_str(".webp");
+
+    // /var/www/cache/path/to
+    let mut webp_dir_absolute_path = PathBuf::from(webp_cache_path);
+    webp_dir_absolute_path.push(&dir_uri_path[1..]);
+
+    // /var/www/cache/path/to/aya.jpg.1582735380.webp
+    let mut webp_img_absolute_path = PathBuf::from(&webp_dir_absolute_path);
+    webp_img_absolute_path.push(&webp_img_name);
+
+    (webp_img_absolute_path, webp_dir_absolute_path, dir_absolute_path)
+}
+
+/// Cache path of a variant of the WebP image, e.g. `/var/www/cache/path/to/aya.jpg.1582735380.lqip.webp`
+fn generate_variant_path(webp_img_absolute_path: &Path, variant: &str, extension: &str) -> PathBuf {
+    // aya.jpg.1582735380
+    let webp_img_name = webp_img_absolute_path.file_name().unwrap().to_str().unwrap();
+    let webp_img_stem = webp_img_name.strip_suffix(".webp").unwrap_or(webp_img_name);
+    webp_img_absolute_path.with_file_name(format!("{}.{}.{}", webp_img_stem, variant, extension))
+}
+
+async fn remove_old_cached_webp(storage: &dyn Storage, webp_img_absolute_path: &Path, img_absolute_path: &Path) {
+    // remove old webp files and their variants
+    // /var/www/cache/path/to/aya.jpg.1582735300.webp      <- older ones will be removed
+    // /var/www/cache/path/to/aya.jpg.1582735300.lqip.webp <-
+    // /var/www/cache/path/to/aya.jpg.1582735380.webp      <- keep the latest ones
+    // /var/www/cache/path/to/aya.jpg.1582735380.lqip.webp <-
+    // aya.jpg.
+    let img_name_prefix = format!("{}.", img_absolute_path.file_name().unwrap().to_str().unwrap());
+    // aya.jpg.1582735380.
+    let webp_img_name = webp_img_absolute_path.file_name().unwrap().to_str().unwrap();
+    let latest_prefix = webp_img_name.strip_suffix("webp").unwrap_or(webp_img_name);
+
+    let paths = match storage.list(&webp_img_absolute_path.with_file_name(&img_name_prefix)).await {
+        Ok(paths) => paths,
+        Err(e) => return eprintln!("{:?}", e),
+    };
+    for path in paths {
+        let name = match path.file_name().and_then(|name| name.to_str()) {
+            Some(name) => name,
+            None => continue,
+        };
+        if name.starts_with(latest_prefix) {
+            continue;
+        }
+        if let Some(rest) = name.strip_prefix(&img_name_prefix) {
+            let modified_time = rest.split('.').next().unwrap_or_default();
+            if !modified_time.is_empty() && modified_time.chars().all(|c| c.is_ascii_digit()) {
+                let _ = storage.delete(&path).await;
+            }
+        }
+    }
+}
+
+/// Value of `name` in the query string, the first one if there are more
+fn query_value<'a>(query: &'a str, name: &str) -> Option<&'a str> {
+    query.split('&').find_map(|pair| {
+        let mut pair = pair.splitn(2, '=');
+        if pair.next() == Some(name) { pair.next() } else { None }
+    })
+}
+
+/// Whether `flag` is in the query string, either as `flag` or `flag=...`
+fn query_flag(query: &str, flag: &str) -> bool {
+    query.split('&').any(|pair| pair.split('=').next() == Some(flag))
+}
+
+/// Loads a variant of the original image from cache, it's generated and cached first if needed.
+/// The generated variant is still returned if it cannot be cached
+async fn load_cached_variant<Generate>(storage: &dyn Storage, conversions: &ConversionPool, webp_img_absolute_path: &Path, img_absolute_path: &Path, variant_path: &Path, generate: Generate) -> Result<Bytes, io::Error> where
+    Generate: 'static + Send + FnOnce() -> Result<Vec<u8>, io::Error> {
+    match storage.get(variant_path).await {
+        Ok(Some(data)) => {
+            conversions.metrics.cache_hit();
+            return Ok(data);
+        },
+        Ok(None) => (),
+        Err(e) => eprintln!("{}", e),
+    }
+    conversions.metrics.cache_miss();
+    let data = Bytes::from(conversions.run(generate).await?);
+    match storage.put(variant_path, &data).await {
+        Ok(()) => remove_old_cached_webp(storage, webp_img_absolute_path, img_absolute_path).await,
+        Err(e) => eprintln!("{}", e),
+    }
+    Ok(data)
+}
+
+/// Sends a variant of the original image from cache, it's generated and cached first if needed
+async fn send_cached_variant<Generate>(storage: &dyn Storage, conversions: &ConversionPool, webp_img_absolute_path: &Path, img_absolute_path: &Path, variant_path: &Path, content_type: &str, generate: Generate) -> Response<Body> where
+    Generate: 'static + Send + FnOnce() -> Result<Vec<u8>, io::Error> {
+    match load_cached_variant(storage, conversions, webp_img_absolute_path, img_absolute_path, variant_path, generate).await {
+        Ok(data) => generate_http_response_builder!(StatusCode::OK, data, content_type),
+        Err(e) => {
+            eprintln!("{}", e);
+            internal_server_error()
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Placeholder {
+    BlurHash,
+    LqipWebP,
+    // for browsers without WebP support
+    LqipPng,
+    // 1x1 image of the dominant color
+    ColorWebP,
+    ColorPng,
+}
+
+const PLACEHOLDER_LQIP_WIDTH: u32 = 16;
+const PLACEHOLDER_LQIP_QUALITY: f32 = 20.0;
+const PLACEHOLDER_BLURHASH_COMPONENTS: (u32, u32) = (4, 3);
+const PALETTE_SIZE: usize = 5;
+
+impl Placeholder {
+    fn variant_path(&self, webp_img_absolute_path: &Path) -> PathBuf {
+        match self {
+            Placeholder::BlurHash => generate_variant_path(webp_img_absolute_path, "blurhash", "txt"),
+            Placeholder::LqipWebP => generate_variant_path(webp_img_absolute_path, "lqip", "webp"),
+            Placeholder::LqipPng => generate_variant_path(webp_img_absolute_path, "lqip", "png"),
+            Placeholder::ColorWebP => generate_variant_path(webp_img_absolute_path, "color", "webp"),
+            Placeholder::ColorPng => generate_variant_path(webp_img_absolute_path, "color", "png"),
+        }
+    }
+
+    fn content_type(&self) -> &'static str {
+        match self {
+            Placeholder::BlurHash => "text/plain",
+            Placeholder::LqipWebP | Placeholder::ColorWebP => "image/webp",
+            Placeholder::LqipPng | Placeholder::ColorPng => "image/png",
+        }
+    }
+
+    fn generate(&self, original_file_path: &str) -> Result<Vec<u8>, io::Error> {
+        let (image, _) = decode_image(original_file_path)?;
+        match self {
+            Placeholder::BlurHash => {
+                // a tiny version is more than enough for a handful of components
+                let thumbnail = image.resize(32, 32, image::imageops::FilterType::Triangle).to_rgba8();
+                let (x_components, y_components) = PLACEHOLDER_BLURHASH_COMPONENTS;
+                Ok(blurhash::encode(&thumbnail, x_components, y_components).into_bytes())
+            },
+            Placeholder::LqipWebP => {
+                let thumbnail = image.resize(PLACEHOLDER_LQIP_WIDTH, u32::MAX, image::imageops::FilterType::Triangle);
+                let mut config = DirectoryLevelConfig::new();
+                config.quality = Some(PLACEHOLDER_LQIP_QUALITY);
+                Ok(encode(thumbnail, &config)?.to_vec())
+            },
+            Placeholder::LqipPng => {
+                let thumbnail = image.resize(PLACEHOLDER_LQIP_WIDTH, u32::MAX, image::imageops::FilterType::Triangle);
+                encode_png(&thumbnail)
+            },
+            Placeholder::ColorWebP | Placeholder::ColorPng => {
+                // fully transparent if there is no opaque pixel at all
+                let pixel = match palette::dominant(&palette_thumbnail(&image)) {
+                    Some(swatch) => image::Rgba([swatch.color[0], swatch.color[1], swatch.color[2], 255]),
+                    None => image::Rgba([0, 0, 0, 0]),
+                };
+                let color = image::DynamicImage::ImageRgba8(image::RgbaImage::from_pixel(1, 1, pixel));
+                if *self == Placeholder::ColorPng {
+                    return encode_png(&color);
+                }
+                let mut config = DirectoryLevelConfig::new();
+                config.lossless = Some(1);
+                Ok(encode(color, &config)?.to_vec())
+            },
+        }
+    }
+}
+
+fn encode_png(image: &image::DynamicImage) -> Result<Vec<u8>, io::Error> {
+    let mut data = Vec::new();
+    if let Err(e) = image.write_to(&mut data, image::ImageOutputFormat::Png) {
+        return Err(io::Error::other(e.to_string()));
+    }
+    Ok(data)
+}
+
+/// A small version is enough for colors, and keeps median cut cheap for large images
+fn palette_thumbnail(image: &image::DynamicImage) -> image::RgbaImage {
+    image.resize(64, 64, image::imageops::FilterType::Triangle).to_rgba8()
+}
+
+/// Colors of an original image, for `?palette`
+#[derive(Debug, Serialize, Deserialize, PartialEq)]
+struct ImagePalette {
+    // `#rrggbb`, or null if the image is fully transparent
+    dominant_color: Option<String>,
+    palette: Vec<String>,
+}
+
+impl ImagePalette {
+    fn read(original_file_path: &str) -> Result<Self, io::Error> {
+        let (image, _) = decode_image(original_file_path)?;
+        let thumbnail = palette_thumbnail(&image);
+        Ok(ImagePalette {
+            dominant_color: palette::dominant(&thumbnail).map(|swatch| swatch.hex()),
+            palette: palette::extract(&thumbnail, PALETTE_SIZE).iter().map(|swatch| swatch.hex()).collect(),
+        })
+    }
+}
+
+/// Metadata of an original image, for `?info`
+#[derive(Debug, Serialize, Deserialize, PartialEq)]
+struct ImageInfo {
+    width: u32,
+    height: u32,
+    aspect_ratio: f64,
+    has_alpha: bool,
+    format: Option<String>,
+    size: u64,
+    // not cached with the rest, the WebP image may be converted or removed at any time
+    webp_size: Option<u64>,
+}
+
+impl ImageInfo {
+    /// Reads metadata from the image header if possible, otherwise the image is decoded
+    fn read(original_file_path: &str) -> Result<Self, io::Error> {
+        use image::ImageDecoder;
+
+        let invalid_data = |e: image::ImageError| io::Error::new(io::ErrorKind::InvalidData, format!("Cannot decode image: {}: {}", original_file_path, e));
+        let reader = image::io::Reader::open(original_file_path)?.with_guessed_format()?;
+        let format = reader.format();
+        let (width, height, has_alpha) = match format {
+            Some(image::ImageFormat::Jpeg) => {
+                let (width, height) = reader.into_dimensions().map_err(invalid_data)?;
+                (width, height, false)
+            },
+            Some(image::ImageFormat::Png) => {
+                let decoder = image::codecs::png::PngDecoder::new(BufReader::new(std::fs::File::open(original_file_path)?)).map_err(invalid_data)?;
+                let (width, height) = decoder.dimensions();
+                (width, height, decoder.color_type().has_alpha())
+            },
+            _ => {
+                let (image, _) = decode_image(original_file_path)?;
+                (image.width(), image.height(), image.color().has_alpha())
+            },
+        };
+        Ok(ImageInfo {
+            width,
+            height,
+            aspect_ratio: if height == 0 { 0.0 } else { width as f64 / height as f64 },
+            has_alpha,
+            format: format.map(|format| format!("{:?}", format).to_lowercase()),
+            size: std::fs::metadata(original_file_path)?.len(),
+            webp_size: None,
+        })
+    }
+}
+
+/// Sends metadata of the original image as JSON, everything but `webp_size` is cached
+async fn send_image_info(storage: &dyn Storage, conversions: &ConversionPool, img_absolute_path: &Path, webp_img_absolute_path: &Path) -> Response<Body> {
+    let info_path = generate_variant_path(webp_img_absolute_path, "info", "json");
+    let original_file_path = img_absolute_path.to_str().unwrap().to_string();
+    let info = load_cached_variant(storage, conversions, webp_img_absolute_path, img_absolute_path, &info_path, move || {
+        Ok(serde_json::to_vec(&ImageInfo::read(&original_file_path)?)?)
+    }).await.and_then(|data| Ok(serde_json::from_slice::<ImageInfo>(&data)?));
+    let mut info = match info {
+        Ok(info) => info,
+        Err(e) => {
+            eprintln!("{}", e);
+            return internal_server_error();
         }
+    };
+
+    info.webp_size = storage.stat(webp_img_absolute_path).await.ok().flatten().map(|stat| stat.size);
+    generate_http_response_builder!(StatusCode::OK, serde_json::to_vec(&info).unwrap(), "application/json")
+}
+
+/// Geometry changes and adjustments requested in the query, or by a named variant, applied right after
+/// decoding, and other per-request variations of the WebP image
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+struct Transform {
+    crop: Option<Crop>,
+    // clockwise in degrees, 90, 180 or 270
+    rotate: Option<u32>,
+    flip: Option<Flip>,
+    width: Option<u32>,
+    height: Option<u32>,
+    // relative position in [0, 1] from the `.focus` sidecar file, only used by `crop=smart`
+    focal_point: Option<(f32, f32)>,
+    // sigma of the gaussian blur
+    blur: Option<f32>,
+    // sigma and threshold of the unsharp mask
+    sharpen: Option<(f32, i32)>,
+    grayscale: bool,
+    // added to each channel
+    brightness: Option<i32>,
+    // in percent, negative values decrease contrast
+    contrast: Option<f32>,
+    // encode with the `save_data` parameters of the directory
+    save_data: bool,
+    // `watermark_key` of the directory, the watermark itself comes from its config
+    watermark: Option<u64>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Crop {
+    Region { x: u32, y: u32, width: u32, height: u32 },
+    // crop to the aspect ratio of width and height, around the focal point or the most salient region
+    Smart,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Flip {
+    Horizontal,
+    Vertical,
+}
+
+const TRANSFORM_MAX_SIGMA: f32 = 50.0;
+/// Largest width or height of a WebP image
+const TRANSFORM_MAX_DIMENSION: u32 = 16383;
+/// Sizes that unsigned widths and heights are rounded up to, unless `transform_sizes` is set
+const TRANSFORM_DEFAULT_SIZES: [u32; 12] = [100, 200, 320, 480, 640, 800, 1024, 1280, 1600, 1920, 2560, 3840];
+
+/// Focal point of `aya.jpg`, stored in `aya.jpg.focus` next to it
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct FocalPoint {
+    x: f32,
+    y: f32,
+}
+
+impl FocalPoint {
+    fn load(img_absolute_path: &Path) -> Result<Option<Self>, String> {
+        let mut sidecar_path = img_absolute_path.as_os_str().to_owned();
+        sidecar_path.push(".focus");
+        let sidecar_path = PathBuf::from(sidecar_path);
+        if !sidecar_path.exists() {
+            return Ok(None);
+        }
+
+        let focal_point: FocalPoint = std::fs::read_to_string(&sidecar_path)
+            .map_err(|e| e.to_string())
+            .and_then(|content| serde_json::from_str(&content).map_err(|e| e.to_string()))
+            .map_err(|e| format!("{}: {}", sidecar_path.display(), e))?;
+        if !(0.0..=1.0).contains(&focal_point.x) || !(0.0..=1.0).contains(&focal_point.y) {
+            return Err(format!("{}: x and y must be in [0, 1]", sidecar_path.display()));
+        }
+        Ok(Some(focal_point))
+    }
+}
+
+impl Transform {
+    /// From `?variant=name`, or from the query itself, which needs a valid signature if `signing_key` is set.
+    /// Without `signing_key`, the query can only resize, to one of a few sizes, so that clients cannot
+    /// make up new cache entries without end
+    fn from_request(config: &WebPServerConfig, img_uri_path: &str, query: &str, img_absolute_path: &Path) -> Result<Self, (StatusCode, String)> {
+        if let Some(name) = query_value(query, "variant") {
+            return match config.variants.as_ref().and_then(|variants| variants.get(name)) {
+                Some(variant) => Transform::from_query(variant, img_absolute_path).map_err(|e| (StatusCode::BAD_REQUEST, e)),
+                None => Err((StatusCode::BAD_REQUEST, format!("unknown variant {}", name))),
+            };
+        }
+
+        let mut transform = Transform::from_query(query, img_absolute_path).map_err(|e| (StatusCode::BAD_REQUEST, e))?;
+        match &config.signing_key {
+            Some(signing_key) => {
+                // /path/to/aya.jpg?width=200&grayscale
+                let unsigned_query: Vec<&str> = query.split('&').filter(|pair| pair.split('=').next() != Some("signature")).collect();
+                let message = format!("{}?{}", img_uri_path, unsigned_query.join("&"));
+                let signature = query_value(query, "signature").unwrap_or_default();
+                if transform != Transform::default() && !signature::verify(signing_key, &message, signature) {
+                    return Err((StatusCode::FORBIDDEN, "invalid signature".to_string()));
+                }
+            },
+            None => {
+                if !transform.is_resize_only() {
+                    return Err((StatusCode::FORBIDDEN, "crop regions and adjustments need a variant, or a signature with signing_key set".to_string()));
+                }
+                let sizes = config.transform_sizes.as_deref().unwrap_or(&TRANSFORM_DEFAULT_SIZES);
+                transform.width = transform.width.map(|width| round_up_to_size(width, sizes));
+                transform.height = transform.height.map(|height| round_up_to_size(height, sizes));
+            },
+        }
+        Ok(transform)
+    }
+
+    /// Whether it only resizes, or crops to the aspect ratio of the new size with `crop=smart`
+    fn is_resize_only(&self) -> bool {
+        let crop = self.crop.filter(|crop| *crop == Crop::Smart);
+        *self == Transform { width: self.width, height: self.height, crop, focal_point: self.focal_point, ..Transform::default() }
+    }
+
+    fn from_query(query: &str, img_absolute_path: &Path) -> Result<Self, String> {
+        let mut transform = Transform::parse(query)?;
+        if transform.crop == Some(Crop::Smart) {
+            // a broken sidecar file is not the fault of the client, fall back to smart crop
+            match FocalPoint::load(img_absolute_path) {
+                Ok(focal_point) => transform.focal_point = focal_point.map(|focal_point| (focal_point.x, focal_point.y)),
+                Err(e) => eprintln!("[ERROR] Invalid focal point\n{}", e),
+            }
+        }
+        Ok(transform)
+    }
+
+    fn parse(query: &str) -> Result<Self, String> {
+        let parse_dimension = |name: &str, value: &str| match value.parse::<u32>() {
+            Ok(dimension) if dimension > 0 && dimension <= TRANSFORM_MAX_DIMENSION => Ok(dimension),
+            _ => Err(format!("{} must be in [1, {}]", name, TRANSFORM_MAX_DIMENSION)),
+        };
+        let parse_sigma = |name: &str, value: &str| match value.parse::<f32>() {
+            Ok(sigma) if sigma > 0.0 && sigma <= TRANSFORM_MAX_SIGMA => Ok(sigma),
+            _ => Err(format!("{} must be in (0, {}]", name, TRANSFORM_MAX_SIGMA)),
+        };
+
+        let mut transform = Transform::default();
+        for pair in query.split('&') {
+            let mut pair = pair.splitn(2, '=');
+            let (key, value) = (pair.next().unwrap_or_default(), pair.next().unwrap_or_default());
+            let value = value.replace("%2C", ",").replace("%2c", ",");
+            match key {
+                "width" => transform.width = Some(parse_dimension("width", &value)?),
+                "height" => transform.height = Some(parse_dimension("height", &value)?),
+                "crop" if value == "smart" => transform.crop = Some(Crop::Smart),
+                "crop" => {
+                    let region: Vec<u32> = value.split(',').map(|value| value.parse::<u32>()).collect::<Result<_, _>>()
+                        .map_err(|_| "crop must be x,y,width,height or smart".to_string())?;
+                    transform.crop = match region[..] {
+                        [x, y, width, height] if width > 0 && height > 0 && [x, y, width, height].iter().all(|value| *value <= TRANSFORM_MAX_DIMENSION) => {
+                            Some(Crop::Region { x, y, width, height })
+                        },
+                        _ => return Err(format!("crop must be x,y,width,height up to {} each, or smart", TRANSFORM_MAX_DIMENSION)),
+                    };
+                },
+                "rotate" => transform.rotate = match value.as_str() {
+                    "90" | "180" | "270" => Some(value.parse().unwrap()),
+                    _ => return Err("rotate must be 90, 180 or 270".to_string()),
+                },
+                "flip" => transform.flip = match value.as_str() {
+                    "h" | "horizontal" => Some(Flip::Horizontal),
+                    "v" | "vertical" => Some(Flip::Vertical),
+                    _ => return Err("flip must be h or v".to_string()),
+                },
+                "blur" => transform.blur = Some(parse_sigma("blur", &value)?),
+                "sharpen" => {
+                    let mut parameters = value.splitn(2, ',');
+                    let sigma = parse_sigma("sharpen", parameters.next().unwrap_or_default())?;
+                    let threshold = match parameters.next().map(|threshold| threshold.parse::<i32>()) {
+                        None => 0,
+                        Some(Ok(threshold)) if (0..=255).contains(&threshold) => threshold,
+                        Some(_) => return Err("threshold of sharpen must be in [0, 255]".to_string()),
+                    };
+                    transform.sharpen = Some((sigma, threshold));
+                },
+                "grayscale" => transform.grayscale = true,
+                "brightness" => transform.brightness = match value.parse::<i32>() {
+                    Ok(brightness) if (-255..=255).contains(&brightness) => Some(brightness),
+                    _ => return Err("brightness must be an integer in [-255, 255]".to_string()),
+                },
+                "contrast" => transform.contrast = match value.parse::<f32>() {
+                    Ok(contrast) if (-100.0..=100.0).contains(&contrast) => Some(contrast),
+                    _ => return Err("contrast must be in [-100, 100]".to_string()),
+                },
+                _ => (),
+            }
+        }
+
+        if transform.crop == Some(Crop::Smart) && (transform.width.is_none() || transform.height.is_none()) {
+            return Err("crop=smart needs both width and height".to_string());
+        }
+        Ok(transform)
+    }
+
+    /// The focal point after rotating and flipping
+    fn oriented_focal_point(&self) -> Option<(f32, f32)> {
+        let (x, y) = self.focal_point?;
+        let (x, y) = match self.rotate {
+            Some(90) => (1.0 - y, x),
+            Some(180) => (1.0 - x, 1.0 - y),
+            Some(270) => (y, 1.0 - x),
+            _ => (x, y),
+        };
+        Some(match self.flip {
+            Some(Flip::Horizontal) => (1.0 - x, y),
+            Some(Flip::Vertical) => (x, 1.0 - y),
+            None => (x, y),
+        })
+    }
+
+    /// Part of the cache file name, e.g. `crop0-0-300-200.w100`
+    fn variant_name(&self) -> String {
+        let mut parts = Vec::new();
+        match (self.crop, self.focal_point) {
+            (Some(Crop::Region { x, y, width, height }), _) => parts.push(format!("crop{}-{}-{}-{}", x, y, width, height)),
+            (Some(Crop::Smart), Some((x, y))) => parts.push(format!("smartcrop{:.4}-{:.4}", x, y)),
+            (Some(Crop::Smart), None) => parts.push("smartcrop".to_string()),
+            (None, _) => (),
+        }
+        if let Some(rotate) = self.rotate {
+            parts.push(format!("rotate{}", rotate));
+        }
+        match self.flip {
+            Some(Flip::Horizontal) => parts.push("fliph".to_string()),
+            Some(Flip::Vertical) => parts.push("flipv".to_string()),
+            None => (),
+        }
+        if let Some(width) = self.width {
+            parts.push(format!("w{}", width));
+        }
+        if let Some(height) = self.height {
+            parts.push(format!("h{}", height));
+        }
+        if let Some(sigma) = self.blur {
+            parts.push(format!("blur{}", sigma));
+        }
+        if let Some((sigma, threshold)) = self.sharpen {
+            parts.push(format!("sharpen{}-{}", sigma, threshold));
+        }
+        if self.grayscale {
+            parts.push("grayscale".to_string());
+        }
+        if let Some(brightness) = self.brightness {
+            parts.push(format!("brightness{}", brightness));
+        }
+        if let Some(contrast) = self.contrast {
+            parts.push(format!("contrast{}", contrast));
+        }
+        if self.save_data {
+            parts.push("savedata".to_string());
+        }
+        if let Some(watermark) = self.watermark {
+            parts.push(format!("wm{:016x}", watermark));
+        }
+        parts.join(".")
+    }
+
+    /// Where the WebP image is cached, variants are next to the plain `webp_img_absolute_path`
+    fn cache_path(&self, webp_img_absolute_path: &Path) -> PathBuf {
+        if *self == Transform::default() {
+            webp_img_absolute_path.to_path_buf()
+        } else {
+            generate_variant_path(webp_img_absolute_path, &self.variant_name(), "webp")
+        }
+    }
+
+    fn apply(&self, mut image: image::DynamicImage) -> Result<image::DynamicImage, io::Error> {
+        if let Some(Crop::Region { x, y, width, height }) = self.crop {
+            let (image_width, image_height) = image.dimensions();
+            if x >= image_width || y >= image_height {
+                return Err(io::Error::new(io::ErrorKind::InvalidInput, format!("crop region is outside of the {}x{} image", image_width, image_height)));
+            }
+            image = image.crop_imm(x, y, min(width, image_width - x), min(height, image_height - y));
+        }
+        image = match self.rotate {
+            Some(90) => image.rotate90(),
+            Some(180) => image.rotate180(),
+            Some(270) => image.rotate270(),
+            _ => image,
+        };
+        image = match self.flip {
+            Some(Flip::Horizontal) => image.fliph(),
+            Some(Flip::Vertical) => image.flipv(),
+            None => image,
+        };
+
+        // images are never upscaled
+        let (image_width, image_height) = image.dimensions();
+        match (self.width, self.height) {
+            (Some(width), Some(height)) if self.crop == Some(Crop::Smart) => {
+                let (crop_width, crop_height) = smartcrop::crop_size(image_width, image_height, width, height);
+                let (x, y) = match self.oriented_focal_point() {
+                    Some((focal_x, focal_y)) => {
+                        let centre_x = (focal_x * image_width as f32) as i64 - crop_width as i64 / 2;
+                        let centre_y = (focal_y * image_height as f32) as i64 - crop_height as i64 / 2;
+                        (centre_x.clamp(0, (image_width - crop_width) as i64) as u32, centre_y.clamp(0, (image_height - crop_height) as i64) as u32)
+                    },
+                    None => smartcrop::find(&image, crop_width, crop_height),
+                };
+                image = image.crop_imm(x, y, crop_width, crop_height);
+                if width < crop_width {
+                    image = image.resize_exact(width, height, image::imageops::FilterType::Lanczos3);
+                }
+            },
+            (None, None) => (),
+            (width, height) => {
+                let (width, height) = (width.unwrap_or(u32::MAX), height.unwrap_or(u32::MAX));
+                if width < image_width || height < image_height {
+                    image = image.resize(width, height, image::imageops::FilterType::Lanczos3);
+                }
+            },
+        }
+
+        // adjustments are cheaper after resizing, and their parameters are relative to the output anyway
+        if let Some(sigma) = self.blur {
+            image = image.blur(sigma);
+        }
+        if let Some((sigma, threshold)) = self.sharpen {
+            image = image.unsharpen(sigma, threshold);
+        }
+        if self.grayscale {
+            image = image.grayscale();
+        }
+        if let Some(brightness) = self.brightness {
+            image = image.brighten(brightness);
+        }
+        if let Some(contrast) = self.contrast {
+            image = image.adjust_contrast(contrast);
+        }
+        Ok(image)
+    }
+}
+
+const FALLBACK_JPEG_QUALITY: u8 = 90;
+
+/// Encodes for browsers without WebP support, PNG for images with alpha and JPEG for the others
+fn encode_fallback(image: &image::DynamicImage) -> Result<Vec<u8>, io::Error> {
+    if image.color().has_alpha() {
+        return encode_png(image);
+    }
+    let mut data = Vec::new();
+    if let Err(e) = image::DynamicImage::ImageRgb8(image.to_rgb8()).write_to(&mut data, image::ImageOutputFormat::Jpeg(FALLBACK_JPEG_QUALITY)) {
+        return Err(io::Error::other(e.to_string()));
+    }
+    Ok(data)
+}
+
+/// Sends the transformed image, generating and caching it first if needed.
+/// Safari users get PNG or JPEG since the original image is not what they asked for
+async fn send_transformed(storage: &dyn Storage, conversions: &ConversionPool, directory_level_config: &DirectoryLevelConfig, img_absolute_path: &Path, webp_img_absolute_path: &Path, transform: &Transform, is_safari: bool) -> Response<Body> {
+    let (original_file_path, directory_level_config, transform) = (img_absolute_path.to_str().unwrap().to_string(), directory_level_config.clone(), *transform);
+    let transformed = if is_safari {
+        let fallback_path = generate_variant_path(webp_img_absolute_path, &transform.variant_name(), "fallback");
+        load_cached_variant(storage, conversions, webp_img_absolute_path, img_absolute_path, &fallback_path, move || {
+            let (image, _, _) = prepare(&original_file_path, &directory_level_config, &transform)?;
+            encode_fallback(&image)
+        }).await
+    } else {
+        load_cached_variant(storage, conversions, webp_img_absolute_path, img_absolute_path, &transform.cache_path(webp_img_absolute_path), move || {
+            Ok(transcode(&original_file_path, &directory_level_config, &transform)?.to_vec())
+        }).await
+    };
+
+    match transformed {
+        Ok(data) => {
+            let content_type = match image::guess_format(&data) {
+                Ok(image::ImageFormat::WebP) => "image/webp",
+                Ok(image::ImageFormat::Png) => "image/png",
+                _ => "image/jpeg",
+            };
+            generate_http_response_builder!(StatusCode::OK, data, content_type)
+        },
+        Err(e) if e.kind() == io::ErrorKind::InvalidInput => generate_http_response_builder!(StatusCode::BAD_REQUEST, e.to_string()),
+        Err(e) => {
+            eprintln!("{}", e);
+            internal_server_error()
+        },
+    }
+}
+
+async fn webp_services(state: Arc<AppState>, req: Request<Body>) -> hyper::Result<Response<Body>> {
+    let (config, storage) = state.current();
+    let response = if req.method() != hyper::Method::GET {
+        method_not_allowed()
+    } else {
+        serve_image(&config, storage.as_ref(), &state.conversions, &state.origin_client, &req).await
+    };
+
+    // image/webp is counted as webp, text/plain as plain
+    let format = response.headers().get(hyper::header::CONTENT_TYPE)
+        .and_then(|content_type| content_type.to_str().ok())
+        .and_then(|content_type| content_type.split(';').next()?.split('/').nth(1))
+        .unwrap_or("none");
+    state.metrics.request(response.status().as_u16(), format);
+    Ok(response)
+}
+
+async fn metrics_services(state: Arc<AppState>, req: Request<Body>) -> hyper::Result<Response<Body>> {
+    if req.method() != hyper::Method::GET {
+        return Ok(method_not_allowed());
+    }
+    if req.uri().path() != "/metrics" {
+        return Ok(not_found());
+    }
+    let hot_cache = state.hot_cache().map(|hot_cache| hot_cache.stats());
+    Ok(generate_http_response_builder!(StatusCode::OK, state.metrics.render(hot_cache), "text/plain; version=0.0.4"))
+}
+
+async fn serve_image(config: &WebPServerConfig, storage: &dyn Storage, conversions: &ConversionPool, origin_client: &HttpClient, req: &Request<Body>) -> Response<Body> {
+    // /path/to/aya.jpg
+    let img_uri_path = req.uri().path();
+    // /IMG_PATH/path/to/aya.jpg
+    let config_img_path = config.img_path.to_string();
+    let mut img_absolute_path = PathBuf::from(&config_img_path);
+    img_absolute_path.push(&img_uri_path[1..]);
+
+    if let Some(origin) = &config.origin {
+        if let Err(e) = origin.fetch(origin_client, img_uri_path, &img_absolute_path).await {
+            eprintln!("[ERROR] Cannot fetch {} from origin: {}", img_uri_path, e);
+            return if e.kind() == io::ErrorKind::InvalidInput { not_found() } else { bad_gateway() };
+        }
+    }
+
+    // Check the original image for existence and ensure its a file
+    let original_img_exists = img_absolute_path.exists();
+    if !original_img_exists || !img_absolute_path.is_file() {
+        return not_found();
+    }
+
+    // Check for Safari users
+    let is_safari = match req.headers().get("user-agent") {
+        Some(ua) => match ua.to_str() {
+            Ok(ua) => ua.contains("Safari") && !ua.contains("Chrome") && !ua.contains("Firefox"),
+            Err(_) => false,
+        },
+        _ => false,
+    };
+
+    let webp_converted_paths = generate_webp_paths(&img_absolute_path, img_uri_path, &config.webp_path);
+    let webp_img_absolute_path = webp_converted_paths.0;
+    let dir_absolute_path = webp_converted_paths.2.to_str().unwrap();
+
+    // nothing is sent if the directory-level config is invalid, neither something cached with settings
+    // that are not what the config file asked for, nor the original image that may need a watermark,
+    // nor placeholders, metadata or palettes of it
+    let directory_level_config = match DirectoryLevelConfig::detect(&config.img_path, dir_absolute_path, &config.global_config) {
+        Ok(directory_level_config) => directory_level_config,
+        Err(e) => {
+            eprintln!("[ERROR] Invalid directory-level config\n{}", e);
+            return internal_server_error();
+        }
+    };
+
+    // Placeholders, Safari users get a PNG one instead of WebP
+    let query = req.uri().query().unwrap_or_default();
+    let placeholder = if query_flag(query, "blurhash") {
+        Some(Placeholder::BlurHash)
+    } else if query_flag(query, "lqip") {
+        Some(if is_safari { Placeholder::LqipPng } else { Placeholder::LqipWebP })
+    } else if query_flag(query, "dominant_color") {
+        Some(if is_safari { Placeholder::ColorPng } else { Placeholder::ColorWebP })
+    } else {
+        None
+    };
+    if let Some(placeholder) = placeholder {
+        let variant_path = placeholder.variant_path(&webp_img_absolute_path);
+        let original_file_path = img_absolute_path.to_str().unwrap().to_string();
+        return send_cached_variant(storage, conversions, &webp_img_absolute_path, &img_absolute_path, &variant_path, placeholder.content_type(), move || {
+            placeholder.generate(&original_file_path)
+        }).await;
+    }
+
+    if query_flag(query, "info") {
+        return send_image_info(storage, conversions, &img_absolute_path, &webp_img_absolute_path).await;
+    }
+    if query_flag(query, "palette") {
+        let palette_path = generate_variant_path(&webp_img_absolute_path, "palette", "json");
+        let original_file_path = img_absolute_path.to_str().unwrap().to_string();
+        return send_cached_variant(storage, conversions, &webp_img_absolute_path, &img_absolute_path, &palette_path, "application/json", move || {
+            Ok(serde_json::to_vec(&ImagePalette::read(&original_file_path)?)?)
+        }).await;
+    }
+
+    let mut transform = match Transform::from_request(config, img_uri_path, query, &img_absolute_path) {
+        Ok(transform) => transform,
+        Err((status_code, e)) => return generate_http_response_builder!(status_code, e),
+    };
+    // dimensions in the query win over Client Hints
+    let client_hints = config.client_hints.as_ref().filter(|_| transform.width.is_none() && transform.height.is_none());
+    if let Some(client_hints) = client_hints {
+        transform.width = client_hints.target_width(req.headers());
+    }
+
+    // a separate variant is only worth it for directories with a save_data block
+    let save_data = req.headers().get("save-data").and_then(|value| value.to_str().ok()).is_some_and(|value| value.trim().eq_ignore_ascii_case("on"));
+    transform.save_data = save_data && !is_safari && directory_level_config.save_data.is_some();
+    // watermarked images are cached under their own name, so that changing the watermark takes effect immediately
+    transform.watermark = directory_level_config.watermark_key();
+    let mut response = send_image(storage, conversions, &directory_level_config, &img_absolute_path, &webp_img_absolute_path, &transform, is_safari).await;
+    // only directories with a save_data block send something else for Save-Data, and never to Safari
+    if directory_level_config.save_data.is_some() && !is_safari && response.status().is_success() {
+        append_vary(&mut response, "Save-Data");
+    }
+    // only images sized by them, nothing else is sent differently for Client Hints
+    if client_hints.is_some() && response.status().is_success() {
+        response.headers_mut().insert("Accept-CH", hyper::header::HeaderValue::from_static(CLIENT_HINTS));
+        append_vary(&mut response, CLIENT_HINTS);
+    }
+    response
+}
+
+/// Sends the original image as WebP, transformed, or as it is if WebP is not an option
+async fn send_image(storage: &dyn Storage, conversions: &ConversionPool, directory_level_config: &DirectoryLevelConfig, img_absolute_path: &Path, webp_img_absolute_path: &Path, transform: &Transform, is_safari: bool) -> Response<Body> {
+    // WebP sources are sent as they are, unless they are transformed or asked to be recompressed
+    if *transform == Transform::default() && directory_level_config.recompress_webp != Some(1) && is_webp_file(img_absolute_path) {
+        return sendfile!(img_absolute_path.to_str().unwrap());
+    }
+    if *transform != Transform::default() {
+        return send_transformed(storage, conversions, directory_level_config, img_absolute_path, webp_img_absolute_path, transform, is_safari).await;
+    }
+
+    if is_safari {
+        return sendfile!(img_absolute_path.to_str().unwrap());
+    }
+
+    let (original_file_path, config) = (img_absolute_path.to_str().unwrap().to_string(), directory_level_config.clone());
+    let converted = load_cached_variant(storage, conversions, webp_img_absolute_path, img_absolute_path, webp_img_absolute_path, move || {
+        Ok(transcode(&original_file_path, &config, &Transform::default())?.to_vec())
+    }).await;
+    match converted {
+        Ok(data) => {
+            if let Ok(metadata) = fs::metadata(img_absolute_path).await {
+                conversions.metrics.bytes_saved(metadata.len(), data.len() as u64);
+            }
+            generate_http_response_builder!(StatusCode::OK, data, "image/webp")
+        },
+        Err(e) => {
+            // send original file if failed
+            eprintln!("{}", e);
+            sendfile!(img_absolute_path.to_str().unwrap())
+        },
+    }
+}
+
+async fn convert(storage: &dyn Storage, conversions: &ConversionPool, original_file_path: &str, webp_file_path: &Path, config: &DirectoryLevelConfig, transform: &Transform) -> Result<(), io::Error> {
+    let (original_file_path, config, transform) = (original_file_path.to_string(), config.clone(), *transform);
+    let encoded_data = conversions.run(move || Ok(transcode(&original_file_path, &config, &transform)?.to_vec())).await?;
+    storage.put(webp_file_path, &encoded_data).await
+}
+
+/// Decodes the original image, applies the transform and encodes the result to WebP
+fn transcode(original_file_path: &str, config: &DirectoryLevelConfig, transform: &Transform) -> Result<WebPData, io::Error> {
+    let (image, format, config) = prepare(original_file_path, config, transform)?;
+    if config.auto_lossless == Some(1) {
+        let (encoded_data, lossless) = encode_auto_lossless(original_file_path, format, image, &config)?;
+        println!("[INFO] {} is encoded {}", original_file_path, if lossless { "lossless" } else { "lossy" });
+        Ok(encoded_data)
+    } else if config.search_target_ssim.is_some() || config.search_target_psnr.is_some() {
+        encode_quality_search(original_file_path, image, &config)
+    } else {
+        Ok(encode(image, &config)?)
+    }
+}
+
+/// Decodes the original image and gets it ready for encoding, returns it with its original format
+/// and the parameters selected for it
+fn prepare(original_file_path: &str, config: &DirectoryLevelConfig, transform: &Transform) -> Result<(image::DynamicImage, Option<image::ImageFormat>, DirectoryLevelConfig), io::Error> {
+    let (image, format) = decode_image(original_file_path)?;
+    let image = transform.apply(image)?;
+    let file_name = Path::new(original_file_path).file_name().and_then(|name| name.to_str()).unwrap_or_default();
+    let mut config = config.select(file_name, format, &image);
+    if transform.save_data {
+        config = config.for_save_data();
+    }
+    let mut image = match config.max_width {
+        Some(max_width) if image.width() > max_width => image.resize(max_width, u32::MAX, image::imageops::FilterType::Lanczos3),
+        _ => image,
+    };
+    if let Some(watermark) = &config.watermark {
+        image = watermark.apply(image)?;
+    }
+    if let Some(background) = config.background.as_deref().and_then(parse_hex_color) {
+        image = flatten_alpha(image, background);
+    } else if config.drop_opaque_alpha == Some(1) && is_opaque(&image) {
+        image = drop_alpha(image);
+    }
+    Ok((image, format, config))
+}
+
+/// Whether the file is a WebP image by its content
+fn is_webp_file(path: &Path) -> bool {
+    image::io::Reader::open(path).and_then(|reader| reader.with_guessed_format()).ok().and_then(|reader| reader.format()) == Some(image::ImageFormat::WebP)
+}
+
+/// `#rrggbb`
+fn parse_hex_color(color: &str) -> Option<[u8; 3]> {
+    let hex = color.strip_prefix('#')?;
+    if hex.len() != 6 || !hex.chars().all(|c| c.is_ascii_hexdigit()) {
+        return None;
+    }
+    let channel = |index: usize| u8::from_str_radix(&hex[index * 2..index * 2 + 2], 16).ok();
+    Some([channel(0)?, channel(1)?, channel(2)?])
+}
+
+/// Composites transparent images onto `background`, 16-bit images stay 16-bit
+fn flatten_alpha(image: image::DynamicImage, background: [u8; 3]) -> image::DynamicImage {
+    if !image.color().has_alpha() {
+        return image;
+    }
+    let over = |channel: u32, alpha: u32, background: u32, max: u32| (channel * alpha + background * (max - alpha) + max / 2) / max;
+    match image {
+        image::DynamicImage::ImageRgba16(_) | image::DynamicImage::ImageLumaA16(_) => {
+            let image = image.into_rgba16();
+            image::DynamicImage::ImageRgb16(image::ImageBuffer::from_fn(image.width(), image.height(), |x, y| {
+                let [r, g, b, a] = image.get_pixel(x, y).0;
+                let channel = |value: u16, index: usize| over(value as u32, a as u32, background[index] as u32 * 257, 65535) as u16;
+                image::Rgb([channel(r, 0), channel(g, 1), channel(b, 2)])
+            }))
+        },
+        _ => {
+            let image = image.into_rgba8();
+            image::DynamicImage::ImageRgb8(image::ImageBuffer::from_fn(image.width(), image.height(), |x, y| {
+                let [r, g, b, a] = image.get_pixel(x, y).0;
+                let channel = |value: u8, index: usize| over(value as u32, a as u32, background[index] as u32, 255) as u8;
+                image::Rgb([channel(r, 0), channel(g, 1), channel(b, 2)])
+            }))
+        },
+    }
+}
+
+fn is_opaque(image: &image::DynamicImage) -> bool {
+    match image {
+        image::DynamicImage::ImageRgba8(image) => image.pixels().all(|pixel| pixel.0[3] == u8::MAX),
+        image::DynamicImage::ImageBgra8(image) => image.pixels().all(|pixel| pixel.0[3] == u8::MAX),
+        image::DynamicImage::ImageLumaA8(image) => image.pixels().all(|pixel| pixel.0[1] == u8::MAX),
+        image::DynamicImage::ImageRgba16(image) => image.pixels().all(|pixel| pixel.0[3] == u16::MAX),
+        image::DynamicImage::ImageLumaA16(image) => image.pixels().all(|pixel| pixel.0[1] == u16::MAX),
+        _ => true,
+    }
+}
+
+/// Same pixels without the alpha channel, so that they are imported as RGB
+fn drop_alpha(image: image::DynamicImage) -> image::DynamicImage {
+    match image {
+        image::DynamicImage::ImageRgba8(_) | image::DynamicImage::ImageBgra8(_) => image::DynamicImage::ImageRgb8(image.into_rgb8()),
+        image::DynamicImage::ImageLumaA8(_) => image::DynamicImage::ImageLuma8(image.into_luma8()),
+        image::DynamicImage::ImageRgba16(_) => image::DynamicImage::ImageRgb16(image.into_rgb16()),
+        image::DynamicImage::ImageLumaA16(_) => image::DynamicImage::ImageLuma16(image.into_luma16()),
+        image => image,
+    }
+}
+
+fn decode_image(original_file_path: &str) -> Result<(image::DynamicImage, Option<image::ImageFormat>), io::Error> {
+    let reader = image::io::Reader::open(original_file_path)?.with_guessed_format()?;
+    let format = reader.format();
+    // the WebP decoder of image only handles lossy images without alpha
+    if format == Some(image::ImageFormat::WebP) {
+        return match decode_webp(&std::fs::read(original_file_path)?) {
+            Some(image) => {
+                // libwebp always decodes to RGBA, even for images without alpha
+                let image = image::DynamicImage::ImageRgba8(image);
+                Ok((if is_opaque(&image) { drop_alpha(image) } else { image }, format))
+            },
+            None => Err(io::Error::new(io::ErrorKind::InvalidData, format!("Cannot decode image: {}: invalid or animated WebP", original_file_path))),
+        };
+    }
+    match reader.decode() {
+        Ok(image) => Ok((image, format)),
+        Err(e) => Err(io::Error::new(io::ErrorKind::InvalidData, format!("Cannot decode image: {}: {}", original_file_path, e))),
+    }
+}
+
+/// Imports pixels of each color type with the matching libwebp importer. 16-bit images are scaled
+/// down to 8-bit and luma images are expanded to RGB, as libwebp only takes 8-bit RGB(A) or BGR(A).
+fn encode(image: image::DynamicImage, config: &DirectoryLevelConfig) -> Result<WebPData, WebPEncodeError> {
+    let encoder = WebPEncoder::new(config)?;
+    let dithering = config.dithering == Some(1);
+    match image {
+        image::DynamicImage::ImageRgb8(image) => {
+            let (width, height) = image.dimensions();
+            encoder.encode(&image.into_raw(), width, height, width * 3, WebPImporter::Rgb)
+        },
+        image::DynamicImage::ImageRgba8(image) => {
+            let (width, height) = image.dimensions();
+            encoder.encode(&image.into_raw(), width, height, width * 4, WebPImporter::Rgba)
+        },
+        image::DynamicImage::ImageBgr8(image) => {
+            let (width, height) = image.dimensions();
+            encoder.encode(&image.into_raw(), width, height, width * 3, WebPImporter::Bgr)
+        },
+        image::DynamicImage::ImageBgra8(image) => {
+            let (width, height) = image.dimensions();
+            encoder.encode(&image.into_raw(), width, height, width * 4, WebPImporter::Bgra)
+        },
+        image::DynamicImage::ImageLuma8(image) => {
+            let image = image::DynamicImage::ImageLuma8(image).into_rgb8();
+            let (width, height) = image.dimensions();
+            encoder.encode(&image.into_raw(), width, height, width * 3, WebPImporter::Rgb)
+        },
+        image::DynamicImage::ImageLumaA8(image) => {
+            let image = image::DynamicImage::ImageLumaA8(image).into_rgba8();
+            let (width, height) = image.dimensions();
+            encoder.encode(&image.into_raw(), width, height, width * 4, WebPImporter::Rgba)
+        },
+        image::DynamicImage::ImageRgb16(image) => {
+            let (width, height) = image.dimensions();
+            let data = samples_to_8bit(&image.into_raw(), width, 3, false, dithering);
+            encoder.encode(&data, width, height, width * 3, WebPImporter::Rgb)
+        },
+        image::DynamicImage::ImageRgba16(image) => {
+            let (width, height) = image.dimensions();
+            let data = samples_to_8bit(&image.into_raw(), width, 4, true, dithering);
+            encoder.encode(&data, width, height, width * 4, WebPImporter::Rgba)
+        },
+        image::DynamicImage::ImageLuma16(image) => {
+            let (width, height) = image.dimensions();
+            let data = samples_to_8bit(&image.into_raw(), width, 1, false, dithering);
+            let image = image::DynamicImage::ImageLuma8(image::GrayImage::from_raw(width, height, data).unwrap()).into_rgb8();
+            encoder.encode(&image.into_raw(), width, height, width * 3, WebPImporter::Rgb)
+        },
+        image::DynamicImage::ImageLumaA16(image) => {
+            let (width, height) = image.dimensions();
+            let data = samples_to_8bit(&image.into_raw(), width, 2, true, dithering);
+            let image = image::DynamicImage::ImageLumaA8(image::GrayAlphaImage::from_raw(width, height, data).unwrap()).into_rgba8();
+            encoder.encode(&image.into_raw(), width, height, width * 4, WebPImporter::Rgba)
+        },
+    }
+}
+
+/// Scales 16-bit samples to 8-bit with rounding. If `dithering` is set, color channels are ordered
+/// dithered to avoid banding in smooth gradients, the alpha channel (the last one) is never dithered.
+fn samples_to_8bit(samples: &[u16], width: u32, channels: usize, has_alpha: bool, dithering: bool) -> Vec<u8> {
+    const BAYER_4X4: [[f32; 4]; 4] = [
+        [0.0, 8.0, 2.0, 10.0],
+        [12.0, 4.0, 14.0, 6.0],
+        [3.0, 11.0, 1.0, 9.0],
+        [15.0, 7.0, 13.0, 5.0],
+    ];
+    let row_length = width as usize * channels;
+    samples.iter().enumerate().map(|(index, &sample)| {
+        let channel = index % channels;
+        let is_alpha = has_alpha && channel == channels - 1;
+        let value = sample as f32 / 257.0;
+        if dithering && !is_alpha && row_length > 0 {
+            let x = (index % row_length) / channels;
+            let y = index / row_length;
+            let threshold = (BAYER_4X4[y % 4][x % 4] + 0.5) / 16.0 - 0.5;
+            (value + threshold).round().clamp(0.0, 255.0) as u8
+        } else {
+            value.round() as u8
+        }
+    }).collect()
+}
+
+const AUTO_LOSSLESS_MAX_PALETTE_SIZE: usize = 256;
+const AUTO_LOSSLESS_DEFAULT_MIN_PSNR: f32 = 40.0;
+
+/// Picks lossless or lossy encoding for the image, returns the encoded data and whether it's lossless.
+///
+/// Images with a small palette (logos, screenshots, charts) are encoded lossless and JPEG sources
+/// are encoded lossy right away. Otherwise both are tried and the smaller one is kept, but the
+/// lossy one only counts if its PSNR against the original image reaches `auto_lossless_min_psnr`.
+fn encode_auto_lossless(original_file_path: &str, format: Option<image::ImageFormat>, image: image::DynamicImage, config: &DirectoryLevelConfig) -> Result<(WebPData, bool), io::Error> {
+    let mut lossless_config = config.clone();
+    lossless_config.lossless = Some(1);
+    let mut lossy_config = config.clone();
+    lossy_config.lossless = Some(0);
+
+    let original = image.to_rgba8();
+    let palette_size = count_colors(&original, AUTO_LOSSLESS_MAX_PALETTE_SIZE + 1);
+    if palette_size <= AUTO_LOSSLESS_MAX_PALETTE_SIZE {
+        println!("[INFO] {} has {} colors, trying lossless only", original_file_path, palette_size);
+        return Ok((encode(image, &lossless_config)?, true));
+    }
+    if format == Some(image::ImageFormat::Jpeg) {
+        println!("[INFO] {} is a JPEG photo, trying lossy only", original_file_path);
+        return Ok((encode(image, &lossy_config)?, false));
+    }
+
+    let lossy = encode(image.clone(), &lossy_config)?;
+    let lossless = encode(image, &lossless_config)?;
+    let lossy_psnr = decode_webp(&lossy).map_or(0.0, |decoded| psnr(&original, &decoded));
+    let min_psnr = config.auto_lossless_min_psnr.unwrap_or(AUTO_LOSSLESS_DEFAULT_MIN_PSNR);
+    println!("[INFO] {}: lossy {} bytes at {:.2} dB, lossless {} bytes", original_file_path, lossy.len(), lossy_psnr, lossless.len());
+    if lossy_psnr >= min_psnr && lossy.len() < lossless.len() {
+        Ok((lossy, false))
+    } else {
+        Ok((lossless, true))
+    }
+}
+
+const SEARCH_DEFAULT_MIN_QUALITY: f32 = 10.0;
+const SEARCH_DEFAULT_MAX_QUALITY: f32 = 95.0;
+const SEARCH_DEFAULT_MAX_ITERATIONS: i32 = 6;
+
+/// Binary searches the lowest quality in [search_min_quality, search_max_quality] whose decoded output
+/// reaches `search_target_ssim` or `search_target_psnr` against the original image. Falls back to
+/// `search_max_quality` if none of the tried qualities reaches the target.
+fn encode_quality_search(original_file_path: &str, image: image::DynamicImage, config: &DirectoryLevelConfig) -> Result<WebPData, io::Error> {
+    let original = image.to_rgba8();
+    let (metric, target) = match config.search_target_ssim {
+        Some(target_ssim) => ("SSIM", target_ssim),
+        None => ("PSNR", config.search_target_psnr.unwrap_or(AUTO_LOSSLESS_DEFAULT_MIN_PSNR)),
+    };
+    let score = |encoded_data: &[u8]| match decode_webp(encoded_data) {
+        Some(decoded) if metric == "SSIM" => ssim(&original, &decoded),
+        Some(decoded) => psnr(&original, &decoded),
+        None => 0.0,
+    };
+
+    let mut low = config.search_min_quality.unwrap_or(SEARCH_DEFAULT_MIN_QUALITY);
+    let mut high = config.search_max_quality.unwrap_or(SEARCH_DEFAULT_MAX_QUALITY);
+    let mut best: Option<(WebPData, f32, f32)> = None;
+    let mut search_config = config.clone();
+    search_config.lossless = Some(0);
+    let mut iterations = 0;
+    while iterations < config.search_max_iterations.unwrap_or(SEARCH_DEFAULT_MAX_ITERATIONS) && high - low > 1.0 {
+        iterations += 1;
+        let quality = ((low + high) / 2.0).round();
+        search_config.quality = Some(quality);
+        let encoded_data = encode(image.clone(), &search_config)?;
+        let encoded_score = score(&encoded_data);
+        if encoded_score >= target {
+            best = Some((encoded_data, quality, encoded_score));
+            high = quality;
+        } else {
+            low = quality;
+        }
+    }
+
+    match best {
+        Some((encoded_data, quality, encoded_score)) => {
+            println!("[INFO] {}: quality {} reaches {} {:.4} after {} iterations", original_file_path, quality, metric, encoded_score, iterations);
+            Ok(encoded_data)
+        },
+        None => {
+            search_config.quality = Some(high);
+            println!("[INFO] {}: {} target {} not reached after {} iterations, using quality {}", original_file_path, metric, target, iterations, high);
+            Ok(encode(image, &search_config)?)
+        }
+    }
+}
+
+/// Mean SSIM over 8x8 windows of the luma channel, pixels are premultiplied by alpha first
+fn ssim(original: &image::RgbaImage, decoded: &image::RgbaImage) -> f32 {
+    if original.dimensions() != decoded.dimensions() {
+        return 0.0;
+    }
+    const WINDOW: u32 = 8;
+    const C1: f64 = (0.01 * 255.0) * (0.01 * 255.0);
+    const C2: f64 = (0.03 * 255.0) * (0.03 * 255.0);
+    let luma = |pixel: &image::Rgba<u8>| {
+        let [r, g, b, a] = pixel.0;
+        (0.299 * r as f64 + 0.587 * g as f64 + 0.114 * b as f64) * a as f64 / 255.0
+    };
+
+    let (width, height) = original.dimensions();
+    let mut total = 0f64;
+    let mut windows = 0u32;
+    for window_y in (0..height).step_by(WINDOW as usize) {
+        for window_x in (0..width).step_by(WINDOW as usize) {
+            let mut samples = Vec::with_capacity((WINDOW * WINDOW) as usize);
+            for y in window_y..min(window_y + WINDOW, height) {
+                for x in window_x..min(window_x + WINDOW, width) {
+                    samples.push((luma(original.get_pixel(x, y)), luma(decoded.get_pixel(x, y))));
+                }
+            }
+            let n = samples.len() as f64;
+            let mean_x = samples.iter().map(|sample| sample.0).sum::<f64>() / n;
+            let mean_y = samples.iter().map(|sample| sample.1).sum::<f64>() / n;
+            let variance_x = samples.iter().map(|sample| (sample.0 - mean_x).powi(2)).sum::<f64>() / n;
+            let variance_y = samples.iter().map(|sample| (sample.1 - mean_y).powi(2)).sum::<f64>() / n;
+            let covariance = samples.iter().map(|sample| (sample.0 - mean_x) * (sample.1 - mean_y)).sum::<f64>() / n;
+            total += ((2.0 * mean_x * mean_y + C1) * (2.0 * covariance + C2))
+                / ((mean_x * mean_x + mean_y * mean_y + C1) * (variance_x + variance_y + C2));
+            windows += 1;
+        }
+    }
+    if windows == 0 {
+        return 1.0;
+    }
+    (total / windows as f64) as f32
+}
+
+/// Number of distinct colors in the image, stops counting at `limit`
+fn count_colors(image: &image::RgbaImage, limit: usize) -> usize {
+    let mut colors = std::collections::HashSet::new();
+    for pixel in image.pixels() {
+        colors.insert(pixel.0);
+        if colors.len() >= limit {
+            break;
+        }
+    }
+    colors.len()
+}
+
+/// PSNR in dB over RGB channels of visible pixels and the alpha channel, capped at 99
+fn psnr(original: &image::RgbaImage, decoded: &image::RgbaImage) -> f32 {
+    if original.dimensions() != decoded.dimensions() {
+        return 0.0;
+    }
+    let mut squared_error = 0f64;
+    let mut samples = 0u64;
+    for (original, decoded) in original.pixels().zip(decoded.pixels()) {
+        // RGB under fully transparent pixels may be discarded by libwebp
+        let channels = if original.0[3] == 0 { 3..4 } else { 0..4 };
+        for channel in channels {
+            let difference = original.0[channel] as f64 - decoded.0[channel] as f64;
+            squared_error += difference * difference;
+            samples += 1;
+        }
+    }
+    if squared_error == 0.0 || samples == 0 {
+        return 99.0;
+    }
+    let mse = squared_error / samples as f64;
+    (10.0 * (255.0 * 255.0 / mse).log10()).min(99.0) as f32
+}
+
+fn decode_webp(data: &[u8]) -> Option<image::RgbaImage> {
+    let mut width: c_int = 0;
+    let mut height: c_int = 0;
+    let decoded = unsafe { webp_decoder(data.as_ptr(), data.len(), &mut width, &mut height) };
+    if decoded.is_null() {
+        return None;
+    }
+    let pixels = unsafe { std::slice::from_raw_parts(decoded, width as usize * height as usize * 4) }.to_vec();
+    unsafe { webp_free(decoded as *mut c_void); };
+    image::RgbaImage::from_raw(width as u32, height as u32, pixels)
+}
+
+fn print_usage(program: &str, opts: Options) {
+    let brief = format!("Usage: {} -c CONF [options]", program);
+    print!("{}", opts.usage(&brief));
+}
+
+fn from_cli_args() -> AppState {
+    let args: Vec<String> = std::env::args().collect();
+    let program = args[0].clone();
+
+    let mut opts = Options::new();
+    opts.optopt("c", "config", "path config file", "CONF");
+    opts.optflag("p", "prefetch", "enable prefetch");
+    opts.optopt("j", "jobs", "max threads for prefetch, [1, num_cpus]", "JOBS");
+    opts.optflag("", "check-config", "validate config file and all directory-level configs, then exit");
+    opts.optopt("", "effective-config", "print the config used for an image or directory under img_path, then exit", "PATH");
+    opts.optflag("h", "help", "print usage");
+    let matches = match opts.parse(&args[1..]) {
+        Ok(m) => { m }
+        Err(f) => { panic!("{}", f.to_string()) }
+    };
+
+    if matches.opt_present("h") {
+        print_usage(&program, opts);
+    }
+
+    let mut prefetch = PrefetchConfig { enabled: false, jobs: 1 };
+    if matches.opt_present("p") {
+        prefetch.enabled = true;
+        prefetch.jobs = num_cpus::get();
+        // cap jobs in [1, num_cpus]
+        if let Some(jobs) = matches.opt_str("j") {
+            prefetch.jobs = min(max(1, jobs.parse::<usize>().unwrap_or(1)), num_cpus::get());
+        }
+    }
+
+    let mut config_path = String::from("./config.json");
+    if let Some(cli_config_path) = matches.opt_str("c") {
+        config_path = cli_config_path.clone();
+    }
+    if matches.opt_present("check-config") {
+        std::process::exit(if check_config(&config_path) { 0 } else { 1 });
+    }
+    if let Some(uri_path) = matches.opt_str("effective-config") {
+        std::process::exit(if print_effective_config(&config_path, &uri_path) { 0 } else { 1 });
+    }
+    let config = match load_config(&config_path) {
+        Ok(value) => value,
+        Err(e) => panic!("[ERROR] Cannot read config file {}", e),
+    };
+    if let Err(e) = config.validate() {
+        panic!("[ERROR] Invalid config file {}: {}", config_path, e);
+    }
+    AppState::new(config_path, config, prefetch)
+}
+
+/// Reports every problem in the config file and in all `.webp-conf` files under `img_path`.
+/// Returns whether all of them are valid.
+fn check_config(config_path: &str) -> bool {
+    let source = match std::fs::read_to_string(config_path) {
+        Ok(source) => source,
+        Err(e) => {
+            eprintln!("{}: {}", config_path, e);
+            return false;
+        }
+    };
+    let config: WebPServerConfig = match serde_json::from_str(&source) {
+        Ok(config) => config,
+        Err(e) => {
+            eprintln!("{}:{}: {}", config_path, e.line(), e);
+            return false;
+        }
+    };
+
+    let mut valid = true;
+    if let Err(invalid_fields) = config.global_config.validate() {
+        valid = false;
+        for invalid in invalid_fields {
+            eprintln!("{}:{}: global_config: {}", config_path, line_of_key(&source, invalid.name).unwrap_or(1), invalid);
+        }
+    }
+    if let Err(e) = config.validate_server() {
+        valid = false;
+        eprintln!("{}: {}", config_path, e);
+    }
+
+    let mut checked = 0usize;
+    let mut invalid = 0usize;
+    // the ones below them would only report the same errors again
+    let mut invalid_directories: Vec<PathBuf> = Vec::new();
+    // parents first, and `.webp-conf` before the directories next to it
+    let walker = WalkDir::new(&config.img_path).sort_by(|a, b| (b.file_name() == ".webp-conf").cmp(&(a.file_name() == ".webp-conf")).then(a.file_name().cmp(b.file_name())));
+    for entry in walker.into_iter().filter_map(|e| e.ok()).filter(|e| e.file_name() == ".webp-conf") {
+        checked += 1;
+        let directory = entry.path().parent().unwrap();
+        if let Err(errors) = DirectoryLevelConfig::load(entry.path()) {
+            invalid += 1;
+            invalid_directories.push(directory.to_path_buf());
+            for error in errors {
+                eprintln!("{}", error);
+            }
+            continue;
+        }
+        if invalid_directories.iter().any(|invalid_directory| directory.starts_with(invalid_directory)) {
+            continue;
+        }
+        // valid on its own, but maybe not along with the ones it inherits from. Directories without
+        // a `.webp-conf` have the effective config of the closest one above them, so they are covered too
+        if let Err(e) = DirectoryLevelConfig::detect(&config.img_path, directory.to_str().unwrap(), &config.global_config) {
+            invalid += 1;
+            eprintln!("{}", e);
+        }
+    }
+    println!("[INFO] {} directory-level config(s) checked, {} invalid", checked, invalid);
+    valid && invalid == 0
+}
+
+/// Prints the effective directory-level config for `uri_path`, e.g. `/path/to/aya.jpg` or `/path/to`
+fn print_effective_config(config_path: &str, uri_path: &str) -> bool {
+    let config = match load_config(config_path) {
+        Ok(config) => config,
+        Err(e) => {
+            eprintln!("[ERROR] Cannot read config file {}", e);
+            return false;
+        }
+    };
+
+    let mut dir_absolute_path = PathBuf::from(&config.img_path);
+    dir_absolute_path.push(uri_path.trim_start_matches('/'));
+    if dir_absolute_path.is_file() {
+        dir_absolute_path.pop();
+    }
+
+    match DirectoryLevelConfig::detect(&config.img_path, dir_absolute_path.to_str().unwrap(), &config.global_config) {
+        Ok(effective_config) => {
+            // only print parameters that are set, the rest are libwebp defaults
+            let mut value = serde_json::to_value(&effective_config).unwrap();
+            remove_null_values(&mut value);
+            println!("{}", serde_json::to_string_pretty(&value).unwrap());
+            true
+        },
+        Err(e) => {
+            eprintln!("{}", e);
+            false
+        }
+    }
+}
+
+fn remove_null_values(value: &mut serde_json::Value) {
+    match value {
+        serde_json::Value::Object(map) => {
+            map.retain(|_, value| !value.is_null());
+            map.values_mut().for_each(remove_null_values);
+        },
+        serde_json::Value::Array(values) => values.iter_mut().for_each(remove_null_values),
+        _ => (),
+    }
+}
+
+/// 1-based line number of the first line that contains `"key"`
+fn line_of_key(source: &str, key: &str) -> Option<usize> {
+    if key.is_empty() {
+        return None;
+    }
+    let quoted_key = format!("\"{}\"", key);
+    source.lines().position(|line| line.contains(&quoted_key)).map(|index| index + 1)
+}
+
+fn load_config<P: AsRef<Path>>(conf_path: P) -> Result<WebPServerConfig, Box<dyn std::error::Error>> {
+    let file = std::fs::File::open(conf_path)?;
+    let reader = BufReader::new(file);
+    let u = serde_json::from_reader(reader)?;
+    Ok(u)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_generate_webp_paths() {
+        let webp_paths = generate_webp_paths(&PathBuf::from("./images/webp-server.jpg"), "/webp-server.jpg", "./cache");
+        assert!(webp_paths.0.eq(&PathBuf::from(format!("./cache/webp-server.jpg.{}.webp", std::fs::metadata("./images/webp-server.jpg").unwrap().modified().unwrap().duration_since(SystemTime::UNIX_EPOCH).unwrap().as_secs()))));
+        assert!(webp_paths.1.eq(&PathBuf::from("./cache/")));
+        assert!(webp_paths.2.eq(&PathBuf::from("./images")));
+    }
+
+    #[test]
+    fn test_generate_variant_path() {
+        let webp_img_absolute_path = PathBuf::from("./cache/path/to/aya.jpg.1582735380.webp");
+        assert_eq!(generate_variant_path(&webp_img_absolute_path, "lqip", "webp"), PathBuf::from("./cache/path/to/aya.jpg.1582735380.lqip.webp"));
+        assert_eq!(generate_variant_path(&webp_img_absolute_path, "blurhash", "txt"), PathBuf::from("./cache/path/to/aya.jpg.1582735380.blurhash.txt"));
+        assert_eq!(Placeholder::LqipPng.variant_path(&webp_img_absolute_path), PathBuf::from("./cache/path/to/aya.jpg.1582735380.lqip.png"));
+    }
+
+    #[tokio::test]
+    async fn test_remove_old_cached_webp() {
+        let webp_dir_absolute_path = PathBuf::from("./cache/test_remove_old_cached_webp");
+        let _ = std::fs::remove_dir_all(&webp_dir_absolute_path);
+        std::fs::create_dir_all(&webp_dir_absolute_path).unwrap();
+        let names = [
+            "aya.jpg.1582735300.webp", "aya.jpg.1582735300.lqip.webp", "aya.jpg.1582735300.blurhash.txt",
+            "aya.jpg.1582735380.webp", "aya.jpg.1582735380.lqip.webp", "aya.jpg.1582735380.blurhash.txt",
+            "aya.jpg.bak.jpg.1582735300.webp", "aya.png.1582735300.webp",
+        ];
+        for name in names.iter() {
+            std::fs::write(webp_dir_absolute_path.join(name), b"").unwrap();
+        }
+
+        remove_old_cached_webp(&FilesystemStorage, &webp_dir_absolute_path.join("aya.jpg.1582735380.webp"), Path::new("./images/aya.jpg")).await;
+        for (name, kept) in names.iter().zip([false, false, false, true, true, true, true, true].iter()) {
+            assert_eq!(webp_dir_absolute_path.join(name).exists(), *kept, "{}", name);
+        }
+        let _ = std::fs::remove_dir_all(&webp_dir_absolute_path);
+    }
+
+    #[test]
+    fn test_placeholders() {
+        let blurhash = String::from_utf8(Placeholder::BlurHash.generate("images/webp-server.jpg").unwrap()).unwrap();
+        assert_eq!(blurhash.len(), 4 + 2 * 11 + 2);
+
+        let lqip = Placeholder::LqipWebP.generate("images/webp-server.jpg").unwrap();
+        let decoded = decode_webp(&lqip).expect("LQIP should be a valid WebP image");
+        assert_eq!(decoded.width(), PLACEHOLDER_LQIP_WIDTH);
+
+        let lqip = Placeholder::LqipPng.generate("images/webp-server.jpg").unwrap();
+        let decoded = image::load_from_memory_with_format(&lqip, image::ImageFormat::Png).unwrap();
+        assert_eq!(decoded.width(), PLACEHOLDER_LQIP_WIDTH);
+
+        assert!(Placeholder::BlurHash.generate("images/does-not-exist.jpg").is_err());
+    }
+
+    #[test]
+    fn test_transform_from_query() {
+        let img_absolute_path = Path::new("./images/webp-server.jpg");
+        assert_eq!(Transform::from_query("", img_absolute_path), Ok(Transform::default()));
+        assert_eq!(Transform::from_query("lqip", img_absolute_path), Ok(Transform::default()));
+
+        let transform = Transform::from_query("crop=10,20,300,200&width=100", img_absolute_path).unwrap();
+        assert_eq!(transform.crop, Some(Crop::Region { x: 10, y: 20, width: 300, height: 200 }));
+        assert_eq!(transform.variant_name(), "crop10-20-300-200.w100");
+        let transform = Transform::from_query("crop=10%2C20%2C300%2C200", img_absolute_path).unwrap();
+        assert_eq!(transform.variant_name(), "crop10-20-300-200");
+        let transform = Transform::from_query("width=160&height=90&crop=smart", img_absolute_path).unwrap();
+        assert_eq!(transform.variant_name(), "smartcrop.w160.h90");
+
+        assert!(Transform::from_query("width=0", img_absolute_path).is_err());
+        assert!(Transform::from_query("height=-1", img_absolute_path).is_err());
+        assert!(Transform::from_query("crop=1,2,3", img_absolute_path).is_err());
+        assert!(Transform::from_query("crop=0,0,0,10", img_absolute_path).is_err());
+        assert!(Transform::from_query("crop=smart&width=100", img_absolute_path).is_err());
+    }
+
+    #[test]
+    fn test_client_hints_target_width() {
+        let client_hints = ClientHintsConfig { breakpoints: vec![1280, 320, 640] };
+        let headers = |hints: &[(&'static str, &'static str)]| {
+            let mut headers = hyper::HeaderMap::new();
+            for (name, value) in hints {
+                headers.insert(*name, hyper::header::HeaderValue::from_static(value));
+            }
+            headers
+        };
+        assert_eq!(client_hints.target_width(&headers(&[])), None);
+        assert_eq!(client_hints.target_width(&headers(&[("sec-ch-width", "300")])), Some(320));
+        assert_eq!(client_hints.target_width(&headers(&[("sec-ch-width", "321"), ("sec-ch-viewport-width", "1000")])), Some(640));
+        assert_eq!(client_hints.target_width(&headers(&[("sec-ch-viewport-width", "400"), ("sec-ch-dpr", "2.5")])), Some(1280));
+        assert_eq!(client_hints.target_width(&headers(&[("sec-ch-viewport-width", "3000")])), Some(1280));
+        assert_eq!(client_hints.target_width(&headers(&[("sec-ch-width", "-1"), ("sec-ch-viewport-width", "320")])), Some(320));
+        assert_eq!(client_hints.target_width(&headers(&[("sec-ch-dpr", "2")])), None);
+
+        assert!(client_hints.validate().is_ok());
+        assert!(ClientHintsConfig { breakpoints: vec![] }.validate().is_err());
+        assert!(ClientHintsConfig { breakpoints: vec![320, 0] }.validate().is_err());
+        assert!(ClientHintsConfig { breakpoints: vec![320, TRANSFORM_MAX_DIMENSION + 1] }.validate().is_err());
+        assert!(ClientHintsConfig { breakpoints: vec![320, TRANSFORM_MAX_DIMENSION] }.validate().is_ok());
+    }
+
+    #[tokio::test]
+    async fn test_save_data() {
+        let mut config = DirectoryLevelConfig::new();
+        config.quality = Some(80.0);
+        config.method = Some(4);
+        let mut save_data = DirectoryLevelConfig::new();
+        save_data.quality = Some(30.0);
+        save_data.max_width = Some(100);
+        config.save_data = Some(Box::new(save_data));
+        assert!(config.validate().is_ok());
+        let save_data_config = config.for_save_data();
+        assert_eq!((save_data_config.quality, save_data_config.method, save_data_config.max_width), (Some(30.0), Some(4), Some(100)));
+        assert!(save_data_config.save_data.is_none());
+
+        config.save_data.as_mut().unwrap().quality = Some(200.0);
+        let invalid_fields = config.validate().unwrap_err();
+        assert!(invalid_fields.iter().any(|invalid| invalid.name == "save_data" && invalid.message.contains("quality")));
+        config.save_data.as_mut().unwrap().quality = Some(30.0);
+        config.save_data.as_mut().unwrap().rules = Some(Vec::new());
+        assert!(config.validate().is_err());
+
+        let dir = TestDir::new("save-data");
+        let img_path = dir.img_path();
+        image::RgbImage::from_fn(300, 200, |x, y| image::Rgb([x as u8, y as u8, 128])).save(format!("{}/photo.png", img_path)).unwrap();
+        std::fs::write(format!("{}/.webp-conf", img_path), r#"{"save_data": {"quality": 30, "max_width": 100}}"#).unwrap();
+        let state = test_state(dir.config());
+
+        let (response, body) = get(&state, "/photo.png", &[("Save-Data", "on")]).await;
+        assert_eq!(response.headers()[hyper::header::VARY], "Save-Data");
+        assert_eq!(decode_webp(&body).unwrap().dimensions(), (100, 66));
+
+        let (response, body) = get(&state, "/photo.png", &[]).await;
+        assert_eq!(response.headers()[hyper::header::VARY], "Save-Data");
+        assert_eq!(decode_webp(&body).unwrap().dimensions(), (300, 200));
+
+        // Safari gets the original image either way
+        let (response, _) = get(&state, "/photo.png", &[("Save-Data", "on"), ("User-Agent", "Version/14.0 Safari/605.1.15")]).await;
+        assert!(response.headers().get(hyper::header::VARY).is_none());
+
+        // listed along with Client Hints
+        let mut config = dir.config();
+        config.client_hints = Some(ClientHintsConfig { breakpoints: vec![320] });
+        state.replace(config);
+        let (response, _) = get(&state, "/photo.png", &[]).await;
+        assert_eq!(response.headers()[hyper::header::VARY], format!("Save-Data, {}", CLIENT_HINTS).as_str());
+        state.replace(dir.config());
+
+        // nothing to vary on without a save_data block, or without an image
+        std::fs::remove_file(format!("{}/.webp-conf", img_path)).unwrap();
+        let (response, _) = get(&state, "/photo.png", &[]).await;
+        assert!(response.headers().get(hyper::header::VARY).is_none());
+        let (response, _) = get(&state, "/missing.png", &[]).await;
+        assert!(response.headers().get(hyper::header::VARY).is_none());
+    }
+
+    #[tokio::test]
+    async fn test_webp_sources() {
+        let dir = TestDir::new("webp-sources");
+        let img_path = dir.img_path();
+        std::fs::create_dir_all(format!("{}/recompressed", img_path)).unwrap();
+        let mut lossless = DirectoryLevelConfig::new();
+        lossless.lossless = Some(1);
+        let translucent = image::RgbaImage::from_fn(40, 20, |x, _| image::Rgba([255, 0, 0, if x < 20 { 255 } else { 100 }]));
+        let original = encode(image::DynamicImage::ImageRgba8(translucent), &lossless).unwrap().to_vec();
+        std::fs::write(format!("{}/photo.webp", img_path), &original).unwrap();
+        std::fs::write(format!("{}/recompressed/photo.webp", img_path), &original).unwrap();
+        std::fs::write(format!("{}/recompressed/.webp-conf", img_path), r#"{"recompress_webp": 1, "quality": 10}"#).unwrap();
+        let opaque = encode(image::DynamicImage::ImageRgb8(image::RgbImage::from_pixel(8, 8, image::Rgb([0, 255, 0]))), &lossless).unwrap().to_vec();
+        std::fs::write(format!("{}/opaque.webp", img_path), &opaque).unwrap();
+
+        // decoded by libwebp, alpha only if there is any
+        let (image, format) = decode_image(&format!("{}/photo.webp", img_path)).unwrap();
+        assert_eq!((image.color(), format), (image::ColorType::Rgba8, Some(image::ImageFormat::WebP)));
+        assert_eq!(image.to_rgba8().get_pixel(30, 0).0, [255, 0, 0, 100]);
+        assert_eq!(decode_image(&format!("{}/opaque.webp", img_path)).unwrap().0.color(), image::ColorType::Rgb8);
+        assert!(is_webp_file(Path::new(&format!("{}/photo.webp", img_path))));
+        assert!(!is_webp_file(Path::new("./images/webp-server.jpg")));
+
+        let mut config = dir.config();
+        config.transform_sizes = Some(vec![10]);
+        let state = test_state(config);
+        let (response, body) = get(&state, "/photo.webp", &[]).await;
+        assert_eq!((response.status(), &body[..]), (StatusCode::OK, &original[..]));
+        let (response, body) = get(&state, "/photo.webp?width=10", &[]).await;
+        assert_eq!(response.status(), StatusCode::OK);
+        let resized = decode_webp(&body).unwrap();
+        assert_eq!(resized.dimensions(), (10, 5));
+        assert!(resized.get_pixel(9, 0).0[3] < 255);
+        let (response, recompressed) = get(&state, "/recompressed/photo.webp", &[]).await;
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_ne!(recompressed, original);
+        assert_eq!(decode_webp(&recompressed).unwrap().dimensions(), (40, 20));
+
+        let mut config = DirectoryLevelConfig::new();
+        config.rules = Some(serde_json::from_str(r#"[{"match": {"format": "webp"}, "config": {"recompress_webp": 1}}]"#).unwrap());
+        assert!(config.validate().is_err());
+    }
+
+    #[tokio::test]
+    async fn test_origin() {
+        use std::sync::Mutex;
+
+        // stand-in for the origin: serves the current image with its ETag, or 404 if there is none
+        let png = |width: u32| {
+            let mut data = Vec::new();
+            image::DynamicImage::ImageRgb8(image::RgbImage::new(width, 10)).write_to(&mut data, image::ImageOutputFormat::Png).unwrap();
+            data
+        };
+        let current = Arc::new(Mutex::new(Some((png(20), "\"v1\""))));
+        let if_none_match = Arc::new(Mutex::new(Vec::<Option<String>>::new()));
+        let (current_copy, if_none_match_copy) = (current.clone(), if_none_match.clone());
+        let server = hyper::Server::bind(&"127.0.0.1:0".parse().unwrap()).serve(make_service_fn(move |_| {
+            let (current, if_none_match) = (current_copy.clone(), if_none_match_copy.clone());
+            async move {
+                Ok::<_, hyper::Error>(service_fn(move |req: Request<Body>| {
+                    let validator = req.headers().get(hyper::header::IF_NONE_MATCH).map(|value| value.to_str().unwrap().to_string());
+                    if_none_match.lock().unwrap().push(validator.clone());
+                    let response = match (req.uri().path(), current.lock().unwrap().clone()) {
+                        ("/static/photo.png", Some((_, etag))) if validator.as_deref() == Some(etag) => Response::builder().status(StatusCode::NOT_MODIFIED).body(Body::empty()),
+                        ("/static/photo.png", Some((data, etag))) => Response::builder().header(hyper::header::ETAG, etag).body(Body::from(data)),
+                        ("/static/large.png", _) | ("/static/slow.png", _) => Response::builder().body(Body::from(vec![0u8; 10000])),
+                        _ => Response::builder().status(StatusCode::NOT_FOUND).body(Body::empty()),
+                    };
+                    let slow = req.uri().path() == "/static/slow.png";
+                    async move {
+                        if slow {
+                            tokio::time::delay_for(Duration::from_secs(3)).await;
+                        }
+                        Ok::<_, hyper::Error>(response.unwrap())
+                    }
+                }))
+            }
+        }));
+        let origin_url = format!("http://{}/static/", server.local_addr());
+        tokio::spawn(server);
+
+        let dir = TestDir::new("origin");
+        let img_path = dir.img_path();
+        let mut config = dir.config();
+        config.origin = Some(OriginConfig { url: origin_url, max_age: None, timeout: Some(1), max_bytes: Some(4096) });
+        assert!(config.origin.as_ref().unwrap().validate().is_ok());
+        let state = test_state(config);
+        let get = |uri: &'static str| {
+            let state = state.clone();
+            async move {
+                let (response, body) = get(&state, uri, &[]).await;
+                (response.status(), webp_width(&body))
+            }
+        };
+
+        assert_eq!(get("/photo.png").await, (StatusCode::OK, Some(20)));
+        let sidecar: OriginValidators = serde_json::from_slice(&std::fs::read(format!("{}/photo.png.origin", img_path)).unwrap()).unwrap();
+        assert_eq!(sidecar.etag.as_deref(), Some("\"v1\""));
+        // revalidated with the ETag, not modified
+        assert_eq!(get("/photo.png").await, (StatusCode::OK, Some(20)));
+        assert_eq!(*if_none_match.lock().unwrap(), vec![None, Some("\"v1\"".to_string())]);
+        // the sidecar file is neither sent nor removed as a missing image
+        assert_eq!(get("/photo.png.origin").await.0, StatusCode::NOT_FOUND);
+        assert!(Path::new(&format!("{}/photo.png.origin", img_path)).exists());
+
+        // too large or too slow
+        assert_eq!(get("/large.png").await.0, StatusCode::BAD_GATEWAY);
+        let started_at = std::time::Instant::now();
+        assert_eq!(get("/slow.png").await.0, StatusCode::BAD_GATEWAY);
+        assert!(started_at.elapsed() < Duration::from_secs(2));
+        assert!(!Path::new(&format!("{}/large.png", img_path)).exists());
+
+        // the new image replaces the cached one
+        *current.lock().unwrap() = Some((png(30), "\"v2\""));
+        next_second().await;
+        assert_eq!(get("/photo.png").await, (StatusCode::OK, Some(30)));
+
+        assert_eq!(get("/missing.png").await.0, StatusCode::NOT_FOUND);
+        *current.lock().unwrap() = None;
+        assert_eq!(get("/photo.png").await.0, StatusCode::NOT_FOUND);
+        assert!(!Path::new(&format!("{}/photo.png", img_path)).exists());
+
+        assert!(OriginConfig { url: "https://example.com".to_string(), max_age: None, timeout: None, max_bytes: None }.validate().is_err());
+        assert!(OriginConfig { url: "/static".to_string(), max_age: None, timeout: None, max_bytes: None }.validate().is_err());
     }
-}
 
-#[derive(Debug, Clone)]
-struct PrefetchConfig {
-    enabled: bool,
-    jobs: usize,
-}
+    #[tokio::test]
+    async fn test_hot_cache_response() {
+        let dir = TestDir::new("hot-cache-response");
+        let photo_path = format!("{}/photo.png", dir.img_path());
+        image::RgbImage::from_pixel(20, 10, image::Rgb([200, 0, 0])).save(&photo_path).unwrap();
+        let mut config = dir.config();
+        config.hot_cache = Some(HotCacheConfig { max_bytes: 1 << 20 });
+        let state = test_state(config);
 
-static mut PREFETCH: PrefetchConfig = PrefetchConfig { enabled: false, jobs: 1 };
+        assert_eq!(webp_width(&get(&state, "/photo.png", &[]).await.1), Some(20));
+        assert_eq!(webp_width(&get(&state, "/photo.png", &[]).await.1), Some(20));
+        let stats = state.hot_cache().unwrap().stats();
+        assert_eq!((stats.hits, stats.misses, stats.entries), (1, 1, 1));
 
-#[tokio::main]
-async fn main() -> std::result::Result<(), Box<dyn std::error::Error + Send + Sync>> {
-    let addr = get_server_listen_options();
-    let server = Server::bind(&addr).serve(make_service_fn(|_| async { Ok::<_, hyper::Error>(service_fn(webp_services)) }));
-    prefetch_if_requested(from_cli_args(), true, ||{});
-    println!("WebP image service on http://{}", addr);
-    server.await?;
-    Ok(())
-}
-
-fn prefetch_if_requested< Callback: 'static + std::marker::Send>(config: WebPServerConfig, verbose: bool, callback: Callback) where
-    Callback: Fn() {
-    let prefetch = unsafe { PREFETCH.clone() };
-
-    if prefetch.enabled {
-        let img_path = String::from(&config.img_path);
-        let img_path_len = img_path.len();
-        let webp_path = String::from(&config.webp_path);
-        let global_config = config.global_config.clone();
-        std::thread::spawn(move || {
-            if verbose { println!("[INFO] Prefetch Started"); }
-            let now = SystemTime::now();
-            let mut filecount = 0usize;
-            let pool = ThreadPool::new(prefetch.jobs);
-            for entry in WalkDir::new(img_path).into_iter().filter_map(|e| e.ok()).filter(|e| e.path().is_file()) {
-                let img_absolute_path = entry.path().to_path_buf();
-                let img_uri_path = String::from(&entry.path().to_str().unwrap()[img_path_len..]);
-                let webp_path_copy = webp_path.clone();
-                filecount += 1;
-                let global_config_copy = global_config.clone();
-                pool.execute(move|| {
-                    let webp_converted_paths = generate_webp_paths(&img_absolute_path, &img_uri_path, &webp_path_copy);
-                    let webp_img_absolute_path = webp_converted_paths.0;
-
-                    if !webp_img_absolute_path.exists() {
-                        let webp_dir_absolute_path = webp_converted_paths.1;
-                        let dir_absolute_path = webp_converted_paths.2.to_str().unwrap();
-
-                        let global_config_copy = global_config_copy.clone();
-                        let directory_level_config = if webp_dir_absolute_path.exists() {
-                            DirectoryLevelConfig::detect(dir_absolute_path, &global_config_copy)
-                        } else {
-                            match std::fs::create_dir_all(&webp_dir_absolute_path) {
-                                Err(_) => return,
-                                _ => DirectoryLevelConfig::detect(dir_absolute_path, &global_config_copy),
-                            }
-                        };
-
-                        // try to convert image to webp format
-                        match convert(img_absolute_path.to_str().unwrap(), webp_img_absolute_path.to_str().unwrap(), &directory_level_config) {
-                            Err(_) => (),
-                            _ => remove_old_cached_webp(&webp_img_absolute_path, &webp_dir_absolute_path, &img_absolute_path),
-                        };
-                    }
-                });
-            }
+        // a new original is a new cache path, the old one is evicted along with its file
+        image::RgbImage::from_pixel(30, 10, image::Rgb([0, 200, 0])).save(&photo_path).unwrap();
+        let modified = SystemTime::now() + Duration::from_secs(10);
+        std::fs::File::options().write(true).open(&photo_path).unwrap().set_modified(modified).unwrap();
+        assert_eq!(webp_width(&get(&state, "/photo.png", &[]).await.1), Some(30));
+        let stats = state.hot_cache().unwrap().stats();
+        assert_eq!((stats.hits, stats.misses, stats.entries), (1, 2, 1));
+    }
 
-            loop {
-                let ticker = tick(Duration::from_micros(500));
-                ticker.recv().unwrap();
-                if pool.queued_count() == 0 && pool.active_count() == 0 {
-                    if verbose { println!("\r[INFO] Prefetch progress: [{}/{}]\n[INFO] Prefetch done, elapsed time: {:.4} seconds", filecount, filecount, now.elapsed().unwrap().as_secs_f32()); }
-                    callback();
-                    return;
-                } else {
-                    if verbose { print!("\r[INFO] Prefetch progress: [{}/{}]", filecount - pool.queued_count() - pool.active_count(), filecount); }
-                    let _ = std::io::stdout().flush();
-                }
-            }
+    #[tokio::test]
+    async fn test_metrics_response() {
+        let dir = TestDir::new("metrics-response");
+        image::RgbImage::from_pixel(20, 10, image::Rgb([200, 0, 0])).save(format!("{}/photo.png", dir.img_path())).unwrap();
+        let mut config = dir.config();
+        config.host = "127.0.0.1".to_string();
+        config.hot_cache = Some(HotCacheConfig { max_bytes: 1 << 20 });
+        config.metrics = Some(MetricsConfig { host: "127.0.0.1".to_string(), port: config.port });
+        assert!(config.validate_server().is_err());
+        config.metrics = Some(MetricsConfig { host: "127.0.0.1".to_string(), port: config.port + 1 });
+        assert!(config.validate_server().is_ok());
+        let state = test_state(config);
+
+        let (response, _) = get(&state, "/photo.png", &[]).await;
+        assert_eq!(response.headers()[hyper::header::CONTENT_TYPE], "image/webp");
+        let (response, _) = get(&state, "/photo.png", &[("User-Agent", "Version/14.0 Safari/605.1.15")]).await;
+        assert_eq!(response.headers()[hyper::header::CONTENT_TYPE], "image/png");
+
+        let response = metrics_services(state.clone(), Request::get("/metrics").body(Body::empty()).unwrap()).await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        assert!(response.headers()[hyper::header::CONTENT_TYPE].to_str().unwrap().starts_with("text/plain"));
+        let body = String::from_utf8(hyper::body::to_bytes(response.into_body()).await.unwrap().to_vec()).unwrap();
+        assert!(body.contains("webp_server_requests_total{status=\"200\",format=\"webp\"} 1\n"));
+        // Safari gets the original image as it is
+        assert!(body.contains("webp_server_requests_total{status=\"200\",format=\"png\"} 1\n"));
+        assert!(body.contains("webp_server_cache_hits_total 0\n"));
+        assert!(body.contains("webp_server_cache_misses_total 1\n"));
+        assert!(body.contains("# TYPE webp_server_conversion_duration_seconds histogram\n"));
+        assert!(body.contains("webp_server_conversion_duration_seconds_count 1\n"));
+        assert!(body.contains("webp_server_conversion_queue_depth 0\n"));
+        assert!(body.contains("webp_server_hot_cache_misses_total 1\n"));
+
+        // served from the hot cache this time
+        let (response, _) = get(&state, "/photo.png", &[]).await;
+        assert_eq!(response.status(), StatusCode::OK);
+        let body = state.metrics.render(state.hot_cache().map(|hot_cache| hot_cache.stats()));
+        assert!(body.contains("webp_server_requests_total{status=\"200\",format=\"webp\"} 2\n"));
+        assert!(body.contains("webp_server_cache_hits_total 1\n"));
+        assert!(body.contains("webp_server_conversion_duration_seconds_count 1\n"));
+        assert!(body.contains("webp_server_hot_cache_hits_total 1\n"));
+
+        let response = metrics_services(state.clone(), Request::get("/photo.png").body(Body::empty()).unwrap()).await.unwrap();
+        assert_eq!(response.status(), StatusCode::NOT_FOUND);
+        let response = metrics_services(state, Request::post("/metrics").body(Body::empty()).unwrap()).await.unwrap();
+        assert_eq!(response.status(), StatusCode::METHOD_NOT_ALLOWED);
+    }
+
+    #[tokio::test]
+    async fn test_conversion_queue_depth() {
+        let metrics = Arc::new(Metrics::new());
+        let conversions = Arc::new(ConversionPool::new(1, Arc::clone(&metrics)));
+        let (release_tx, release_rx) = std::sync::mpsc::channel::<()>();
+        let running = tokio::spawn({
+            let conversions = Arc::clone(&conversions);
+            async move { conversions.run(move || release_rx.recv().map_err(io::Error::other)).await }
         });
+        let waiting = tokio::spawn({
+            let conversions = Arc::clone(&conversions);
+            async move { conversions.run(|| Ok(())).await }
+        });
+        // one conversion holds the only worker, the other one waits for it
+        tokio::time::delay_for(Duration::from_millis(50)).await;
+        assert!(metrics.render(None).contains("webp_server_conversion_queue_depth 2\n"));
+
+        release_tx.send(()).unwrap();
+        running.await.unwrap().unwrap();
+        waiting.await.unwrap().unwrap();
+        let output = metrics.render(None);
+        assert!(output.contains("webp_server_conversion_queue_depth 0\n"));
+        assert!(output.contains("webp_server_conversion_duration_seconds_count 2\n"));
     }
-}
 
-fn generate_webp_paths(img_absolute_path: &PathBuf, img_uri_path: &str, webp_cache_path: &str) -> (PathBuf, PathBuf, PathBuf) {
-    // aya.jpg
-    let img_name = img_absolute_path.file_name().unwrap().to_str().unwrap();
-    // /path/to
-    let mut dir_uri_path = PathBuf::from(&img_uri_path);
-    dir_uri_path.pop();
-    let dir_uri_path = dir_uri_path.to_str().unwrap();
-    // /IMG_PATH/path/to/
-    let mut dir_absolute_path = PathBuf::from(&img_absolute_path);
-    dir_absolute_path.pop();
+    #[tokio::test]
+    async fn test_client_hints_response() {
+        let dir = TestDir::new("client-hints-response");
+        let mut config = dir.config();
+        config.img_path = "./images".to_string();
+        config.client_hints = Some(ClientHintsConfig { breakpoints: vec![320] });
+        let state = test_state(config);
 
-    // 1582735380
-    let modified_time = match std::fs::metadata(&img_absolute_path) {
-        Ok(metadata) => match metadata.modified() {
-            Ok(modified_time) => modified_time.duration_since(SystemTime::UNIX_EPOCH).unwrap().as_secs(),
-            Err(e) => {
-                eprintln!("{}", e);
-                0
-            }
-        },
-        Err(e) => {
-            eprintln!("{}", e);
-            0
+        let (response, body) = get(&state, "/webp-server.jpg", &[("Sec-CH-Width", "200")]).await;
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(response.headers()["Accept-CH"], CLIENT_HINTS);
+        assert_eq!(response.headers()[hyper::header::VARY], CLIENT_HINTS);
+        assert_eq!(webp_width(&body), Some(320));
+
+        // explicit width wins, so the response does not vary on the hints
+        let (response, body) = get(&state, "/webp-server.jpg?width=100", &[("Sec-CH-Width", "200")]).await;
+        assert!(response.headers().get("Accept-CH").is_none() && response.headers().get(hyper::header::VARY).is_none());
+        assert_eq!(webp_width(&body), Some(100));
+
+        // neither do errors nor metadata
+        for uri in &["/missing.jpg", "/webp-server.jpg?info", "/webp-server.jpg?palette"] {
+            let (response, _) = get(&state, uri, &[]).await;
+            assert!(response.headers().get("Accept-CH").is_none() && response.headers().get(hyper::header::VARY).is_none(), "{}", uri);
         }
-    };
+    }
 
-    // aya.jpg.1582735380.webp
-    let mut webp_img_name = String::from(img_name);
-    webp_img_name.push('.');
-    webp_img_name.push_str(&modified_time.to_string());
-    webp_img_name.push_str(".webp");
+    #[test]
+    fn test_watermark() {
+        let _ = std::fs::create_dir_all("./cache");
+        let watermark_path = "./cache/test_watermark.png";
+        image::RgbaImage::from_pixel(10, 10, image::Rgba([255, 255, 255, 255])).save(watermark_path).unwrap();
+        let mut watermark = WatermarkConfig { image: watermark_path.to_string(), position: Some("top-left".to_string()), margin: Some(0), opacity: Some(1.0), scale: None };
+        assert!(watermark.validate().is_ok());
 
-    // /var/www/cache/path/to
-    let mut webp_dir_absolute_path = PathBuf::from(webp_cache_path);
-    webp_dir_absolute_path.push(&dir_uri_path[1..]);
+        let image = image::DynamicImage::ImageRgb8(image::RgbImage::from_pixel(100, 50, image::Rgb([255, 0, 0])));
+        let watermarked = watermark.apply(image.clone()).unwrap();
+        assert_eq!(watermarked.color(), image::ColorType::Rgb8);
+        let watermarked = watermarked.to_rgb8();
+        assert_eq!(watermarked.get_pixel(0, 0).0, [255, 255, 255]);
+        assert_eq!(watermarked.get_pixel(19, 19).0, [255, 255, 255]);
+        assert_eq!(watermarked.get_pixel(20, 20).0, [255, 0, 0]);
 
-    // /var/www/cache/path/to/aya.jpg.1582735380.webp
-    let mut webp_img_absolute_path = PathBuf::from(&webp_dir_absolute_path);
-    webp_img_absolute_path.push(&webp_img_name);
+        watermark.position = Some("bottom-right".to_string());
+        watermark.margin = Some(5);
+        watermark.opacity = Some(0.5);
+        let watermarked = watermark.apply(image).unwrap().to_rgb8();
+        assert_eq!(watermarked.get_pixel(94, 44).0, [255, 128, 128]);
+        assert_eq!(watermarked.get_pixel(95, 45).0, [255, 0, 0]);
+        assert_eq!(watermarked.get_pixel(75, 25).0, [255, 128, 128]);
+        assert_eq!(watermarked.get_pixel(74, 24).0, [255, 0, 0]);
 
-    (webp_img_absolute_path, webp_dir_absolute_path, dir_absolute_path)
-}
+        // translucent images keep their alpha channel
+        let image = image::DynamicImage::ImageRgba8(image::RgbaImage::from_pixel(100, 50, image::Rgba([255, 0, 0, 0])));
+        let watermarked = watermark.apply(image).unwrap();
+        assert_eq!(watermarked.color(), image::ColorType::Rgba8);
+        assert_eq!(watermarked.to_rgba8().get_pixel(94, 44).0, [255, 255, 255, 128]);
 
-fn remove_old_cached_webp(webp_img_absolute_path: &PathBuf, webp_dir_absolute_path: &PathBuf, img_absolute_path: &PathBuf) {
-    // remove old webp files
-    // /var/www/cache/path/to/aya.jpg.1582735300.webp <- older ones will be removed
-    // /var/www/cache/path/to/aya.jpg.1582735380.webp <- keep the latest one
-    let mut glob_pattern = String::from(webp_dir_absolute_path.canonicalize().unwrap().to_str().unwrap());
-    glob_pattern.push_str(&format!("/{}.*.webp", img_absolute_path.file_name().unwrap().to_str().unwrap()));
-
-    if let Ok(webp_img_absolute_path) = webp_img_absolute_path.canonicalize() {
-        if let Some(webp_img_absolute_path) = webp_img_absolute_path.to_str() {
-            for entry in glob(&glob_pattern).expect("Failed to read glob pattern") {
-                match entry {
-                    Ok(path) => if let Some(path) = path.to_str() {
-                        if path != webp_img_absolute_path {
-                            let _ = std::fs::remove_file(&path);
-                        }
-                    },
-                    Err(e) => eprintln!("{:?}", e),
-                }
-            }
-        }
+        let mut config = DirectoryLevelConfig::new();
+        assert_eq!(config.watermark_key(), None);
+        config.watermark = Some(watermark.clone());
+        let key = config.watermark_key();
+        assert!(key.is_some());
+        config.watermark.as_mut().unwrap().opacity = Some(0.3);
+        assert_ne!(config.watermark_key(), key);
+        let transform = Transform { watermark: Some(0xabc), ..Transform::default() };
+        assert_eq!(transform.variant_name(), "wm0000000000000abc");
+
+        watermark.opacity = Some(1.5);
+        assert!(watermark.validate().is_err());
+        watermark.opacity = None;
+        watermark.position = Some("middle".to_string());
+        assert!(watermark.validate().is_err());
+        watermark.position = None;
+        watermark.scale = Some(0.0);
+        assert!(watermark.validate().is_err());
+        watermark.scale = None;
+        let _ = std::fs::remove_file(watermark_path);
+        assert!(watermark.validate().is_err());
     }
-}
 
-async fn webp_services(req: Request<Body>) -> hyper::Result<Response<Body>> {
-    if req.method() != hyper::Method::GET {
-        Ok(method_not_allowed())
-    } else {
-        let config = from_cli_args();
-        // /path/to/aya.jpg
-        let img_uri_path = req.uri().path();
-        // /IMG_PATH/path/to/aya.jpg
-        let config_img_path = config.img_path.to_string();
-        let mut img_absolute_path = PathBuf::from(&config_img_path);
-        img_absolute_path.push(&img_uri_path[1..]);
-
-        // Check the original image for existence and ensure its a file
-        let original_img_exists = img_absolute_path.exists();
-        if !original_img_exists || !img_absolute_path.is_file() {
-            return Ok(not_found());
-        }
-
-        // Check for Safari users
-        let is_safari = match req.headers().get("user-agent") {
-            Some(ua) => match ua.to_str() {
-                Ok(ua) => ua.contains("Safari") && !ua.contains("Chrome") && !ua.contains("Firefox"),
-                Err(_) => false,
-            },
-            _ => false,
-        };
-        if is_safari {
-            return Ok(sendfile!(img_absolute_path.to_str().unwrap()))
-        }
+    #[test]
+    fn test_flatten_alpha() {
+        assert_eq!(parse_hex_color("#ff8000"), Some([255, 128, 0]));
+        assert_eq!(parse_hex_color("#FF8000"), Some([255, 128, 0]));
+        assert_eq!(parse_hex_color("ff8000"), None);
+        assert_eq!(parse_hex_color("#f80"), None);
+        assert_eq!(parse_hex_color("#ff800g"), None);
 
-        let webp_converted_paths = generate_webp_paths(&img_absolute_path, img_uri_path, &config.webp_path);
-        let webp_img_absolute_path = webp_converted_paths.0;
-        let webp_dir_absolute_path = webp_converted_paths.1;
-        let dir_absolute_path = webp_converted_paths.2.to_str().unwrap();
+        let image = image::DynamicImage::ImageRgba8(image::RgbaImage::from_fn(2, 1, |x, _| image::Rgba([0, 0, 0, if x == 0 { 0 } else { 128 }])));
+        let flattened = flatten_alpha(image, [255, 255, 255]);
+        assert_eq!(flattened.color(), image::ColorType::Rgb8);
+        assert_eq!(flattened.to_rgb8().get_pixel(0, 0).0, [255, 255, 255]);
+        assert_eq!(flattened.to_rgb8().get_pixel(1, 0).0, [127, 127, 127]);
+        let image = image::DynamicImage::ImageLumaA16(image::ImageBuffer::from_pixel(1, 1, image::LumaA([65535u16, 0])));
+        let flattened = flatten_alpha(image, [0, 0, 255]);
+        assert_eq!(flattened.color(), image::ColorType::Rgb16);
+        assert_eq!(flattened.to_rgb16().get_pixel(0, 0).0, [0, 0, 65535]);
 
-        return if webp_img_absolute_path.exists() {
-            Ok(sendfile!(webp_img_absolute_path.to_str().unwrap()))
-        } else {
-            // send original file if we cannot create cache directory or subdirectory
-            let directory_level_config = match fs::create_dir_all(&webp_dir_absolute_path).await {
-                Ok(()) => DirectoryLevelConfig::detect(dir_absolute_path, &config.global_config),
-                Err(e) => {
-                    eprintln!("{}", e);
-                    return Ok(sendfile!(img_absolute_path.to_str().unwrap()));
-                }
-            };
+        let opaque = image::DynamicImage::ImageRgba8(image::RgbaImage::from_pixel(2, 2, image::Rgba([1, 2, 3, 255])));
+        assert!(is_opaque(&opaque));
+        assert_eq!(drop_alpha(opaque).color(), image::ColorType::Rgb8);
+        assert!(!is_opaque(&image::DynamicImage::ImageLumaA8(image::ImageBuffer::from_pixel(1, 1, image::LumaA([0, 254])))));
 
-            // try to convert image to webp format
-            match convert(img_absolute_path.to_str().unwrap(), webp_img_absolute_path.to_str().unwrap(), &directory_level_config) {
-                Err(e) => {
-                    // send original file if failed
-                    eprintln!("{}", e);
-                    Ok(sendfile!(img_absolute_path.to_str().unwrap()))
-                },
-                _ => {
-                    remove_old_cached_webp(&webp_img_absolute_path, &webp_dir_absolute_path, &img_absolute_path);
-                    Ok(sendfile!(webp_img_absolute_path))
-                },
-            }
-        }
+        // applied after everything else in `prepare`
+        let _ = std::fs::create_dir_all("./cache");
+        let png_path = "./cache/test_flatten_alpha.png";
+        image::RgbaImage::from_pixel(4, 4, image::Rgba([10, 20, 30, 255])).save(png_path).unwrap();
+        let mut config = DirectoryLevelConfig::new();
+        assert_eq!(prepare(png_path, &config, &Transform::default()).unwrap().0.color(), image::ColorType::Rgba8);
+        config.drop_opaque_alpha = Some(1);
+        assert_eq!(prepare(png_path, &config, &Transform::default()).unwrap().0.color(), image::ColorType::Rgb8);
+        let _ = std::fs::remove_file(png_path);
+
+        config.background = Some("white".to_string());
+        assert!(config.validate().unwrap_err().iter().any(|invalid| invalid.name == "background"));
+        config.background = Some("#ffffff".to_string());
+        config.drop_opaque_alpha = Some(2);
+        assert!(config.validate().unwrap_err().iter().any(|invalid| invalid.name == "drop_opaque_alpha"));
     }
-}
 
-fn convert(original_file_path: &str, webp_file_path: &str, config: &DirectoryLevelConfig) -> Result<(), io::Error> {
-    match image::open(original_file_path) {
-        Ok(image) => {
-            static WEBP_PICTURE_IMPORT_RGB: i32 = 1;
-            static WEBP_PICTURE_IMPORT_RGBA: i32 = 2;
-            static WEBP_PICTURE_IMPORT_BGR: i32 = 3;
-            static WEBP_PICTURE_IMPORT_BGRA: i32 = 4;
+    #[test]
+    fn test_transform_apply() {
+        let image = image::DynamicImage::ImageRgb8(image::RgbImage::from_fn(400, 300, |x, y| image::Rgb([(x / 2) as u8, (y / 2) as u8, 0])));
 
-            let encoded_size: size_t;
-            let encoded_data: *mut c_uchar = null_mut();
-            let config_c_ptr = config.to_c_config_ptr();
+        let transform = Transform { crop: Some(Crop::Region { x: 100, y: 50, width: 1000, height: 100 }), ..Transform::default() };
+        let cropped = transform.apply(image.clone()).unwrap();
+        assert_eq!(cropped.dimensions(), (300, 100));
+        assert_eq!(cropped.to_rgb8().get_pixel(0, 0).0, [50, 25, 0]);
+        let transform = Transform { crop: Some(Crop::Region { x: 400, y: 0, width: 10, height: 10 }), ..Transform::default() };
+        assert_eq!(transform.apply(image.clone()).unwrap_err().kind(), io::ErrorKind::InvalidInput);
 
-            match image {
-                image::DynamicImage::ImageBgr8(image) => {
-                    let width = image.width() as i32;
-                    let height = image.height() as i32;
-                    let data = image.into_raw();
-                    let data_ptr = data.as_ptr();
-                    let stride = width * 3;
-                    encoded_size = unsafe { webp_encoder(data_ptr, width, height, stride,
-                                          WEBP_PICTURE_IMPORT_BGR, config_c_ptr, &encoded_data) };
-                },
-                image::DynamicImage::ImageRgb8(image) => {
-                    let width = image.width() as i32;
-                    let height = image.height() as i32;
-                    let data = image.into_raw();
-                    let data_ptr = data.as_ptr();
-                    let stride = width * 3;
-                    encoded_size = unsafe { webp_encoder(data_ptr, width, height, stride,
-                                                         WEBP_PICTURE_IMPORT_RGB, config_c_ptr,&encoded_data) };
-                }
-                image::DynamicImage::ImageBgra8(image) => {
-                    let width = image.width() as i32;
-                    let height = image.height() as i32;
-                    let data = image.into_raw();
-                    let data_ptr = data.as_ptr();
-                    let stride = width * 4;
-                    encoded_size = unsafe { webp_encoder(data_ptr, width, height, stride,
-                                                         WEBP_PICTURE_IMPORT_BGRA, config_c_ptr,&encoded_data) };
-                },
-                image::DynamicImage::ImageRgba8(image) => {
-                    let width = image.width() as i32;
-                    let height = image.height() as i32;
-                    let data = image.into_raw();
-                    let data_ptr = data.as_ptr();
-                    let stride = width * 4;
-                    encoded_size = unsafe { webp_encoder(data_ptr, width, height, stride,
-                                                         WEBP_PICTURE_IMPORT_BGRA, config_c_ptr,&encoded_data) };
-                }
-                image::DynamicImage::ImageRgb16(_) | image::DynamicImage::ImageLuma8(_) | image::DynamicImage::ImageLuma16(_) => {
-                    let image = image.into_rgb();
-                    let width = image.width() as i32;
-                    let height = image.height() as i32;
-                    let data = image.into_raw();
-                    let data_ptr = data.as_ptr();
-                    let stride = width * 3;
-                    encoded_size = unsafe { webp_encoder(data_ptr, width, height, stride,
-                                                         WEBP_PICTURE_IMPORT_RGB, config_c_ptr,&encoded_data) };
-                },
-                image::DynamicImage::ImageRgba16(_) | image::DynamicImage::ImageLumaA8(_) | image::DynamicImage::ImageLumaA16(_) => {
-                    let image = image.into_rgba();
-                    let width = image.width() as i32;
-                    let height = image.height() as i32;
-                    let data = image.into_raw();
-                    let data_ptr = data.as_ptr();
-                    let stride = width * 4;
-                    encoded_size = unsafe { webp_encoder(data_ptr, width, height, stride,
-                                                         WEBP_PICTURE_IMPORT_RGBA,config_c_ptr,&encoded_data) };
-                }
-            };
-            unsafe { drop_webpwrapper_config(config_c_ptr); };
+        // fit inside, never upscaled
+        let transform = Transform { width: Some(200), height: Some(200), ..Transform::default() };
+        assert_eq!(transform.apply(image.clone()).unwrap().dimensions(), (200, 150));
+        let transform = Transform { height: Some(600), ..Transform::default() };
+        assert_eq!(transform.apply(image.clone()).unwrap().dimensions(), (400, 300));
 
-            let encoded_data : Vec<u8> = unsafe { Vec::from_raw_parts(encoded_data, encoded_size, encoded_size) };
-            let mut file = std::fs::File::create(&webp_file_path)?;
-            file.write_all(&encoded_data)?;
-            Ok(())
-        },
-        Err(e) => Err(std::io::Error::new( std::io::ErrorKind::InvalidData, format!("Cannot decode image: {}: {}", original_file_path, e) )),
+        // crop around the focal point near the right edge, clamped to the image
+        let transform = Transform { crop: Some(Crop::Smart), width: Some(100), height: Some(100), focal_point: Some((0.9, 0.5)), ..Transform::default() };
+        let cropped = transform.apply(image.clone()).unwrap();
+        assert_eq!(cropped.dimensions(), (100, 100));
+        let transform = Transform { crop: Some(Crop::Smart), width: Some(1000), height: Some(1000), focal_point: Some((0.9, 0.5)), ..Transform::default() };
+        let cropped = transform.apply(image).unwrap();
+        assert_eq!(cropped.dimensions(), (300, 300));
+        assert_eq!(cropped.to_rgb8().get_pixel(0, 0).0, [50, 0, 0]);
     }
-}
 
-fn print_usage(program: &str, opts: Options) {
-    let brief = format!("Usage: {} -c CONF [options]", program);
-    print!("{}", opts.usage(&brief));
-}
+    #[test]
+    fn test_transform_adjustments() {
+        let transform = Transform::parse("rotate=90&flip=h&blur=1.5&sharpen=2,5&grayscale&brightness=-20&contrast=10").unwrap();
+        assert_eq!(transform.variant_name(), "rotate90.fliph.blur1.5.sharpen2-5.grayscale.brightness-20.contrast10");
+        assert_eq!(Transform::parse("sharpen=1").unwrap().sharpen, Some((1.0, 0)));
+        for query in &["rotate=45", "flip=d", "blur=0", "blur=100", "sharpen=1,300", "brightness=256", "contrast=-101"] {
+            assert!(Transform::parse(query).is_err(), "{} should be rejected", query);
+        }
 
-fn from_cli_args() -> WebPServerConfig {
-    static mut ONCE_TOKEN: bool = false;
-    static mut CONFIG: WebPServerConfig = WebPServerConfig {
-        host: String::new(),
-        port: 0,
-        img_path: String::new(),
-        webp_path: String::new(),
-        global_config: DirectoryLevelConfig::new(),
-    };
-    if unsafe { ONCE_TOKEN } {
-        unsafe { CONFIG.clone() }
-    } else {
-        let args: Vec<String> = std::env::args().collect();
-        let program = args[0].clone();
-
-        let mut opts = Options::new();
-        opts.optopt("c", "config", "path config file", "CONF");
-        opts.optflag("p", "prefetch", "enable prefetch");
-        opts.optopt("j", "jobs", "max threads for prefetch, [1, num_cpus]", "JOBS");
-        opts.optflag("h", "help", "print usage");
-        let matches = match opts.parse(&args[1..]) {
-            Ok(m) => { m }
-            Err(f) => { panic!(f.to_string()) }
-        };
+        let image = image::DynamicImage::ImageRgb8(image::RgbImage::from_fn(400, 300, |x, _| if x < 200 { image::Rgb([255, 0, 0]) } else { image::Rgb([0, 0, 255]) }));
+        let rotated = Transform::parse("rotate=90").unwrap().apply(image.clone()).unwrap();
+        assert_eq!(rotated.dimensions(), (300, 400));
+        assert_eq!(rotated.to_rgb8().get_pixel(0, 0).0, [255, 0, 0]);
+        let flipped = Transform::parse("flip=h").unwrap().apply(image.clone()).unwrap();
+        assert_eq!(flipped.to_rgb8().get_pixel(0, 0).0, [0, 0, 255]);
+        let gray = Transform::parse("grayscale&brightness=10").unwrap().apply(image).unwrap();
+        assert_eq!(gray.color(), image::ColorType::L8);
+        assert_eq!(gray.to_luma8().get_pixel(0, 0).0, [64]);
 
-        if matches.opt_present("h") {
-            print_usage(&program, opts);
-        }
+        // the focal point turns with the image
+        let transform = Transform { rotate: Some(90), focal_point: Some((0.25, 0.75)), ..Transform::default() };
+        assert_eq!(transform.oriented_focal_point(), Some((0.25, 0.25)));
+        let transform = Transform { rotate: Some(270), flip: Some(Flip::Vertical), focal_point: Some((0.25, 0.75)), ..Transform::default() };
+        assert_eq!(transform.oriented_focal_point(), Some((0.75, 0.25)));
+    }
 
-        if matches.opt_present("p") {
-            unsafe { PREFETCH.enabled = true; PREFETCH.jobs = num_cpus::get(); };
-            // cap jobs in [1, num_cpus]
-            if let Some(jobs) = matches.opt_str("j") {
-                unsafe { PREFETCH.jobs = min(max(1, jobs.parse::<usize>().unwrap_or(1)), num_cpus::get()); };
+    #[tokio::test]
+    async fn test_variants_and_signatures() {
+        let dir = TestDir::new("variants-and-signatures");
+        let mut config = dir.config();
+        config.img_path = "./images".to_string();
+        config.variants = Some([("thumb".to_string(), "width=100&grayscale".to_string())].iter().cloned().collect());
+        config.host = "127.0.0.1".to_string();
+        config.signing_key = Some("secret".to_string());
+        assert!(config.validate_server().is_ok());
+        let state = test_state(config.clone());
+        let status_and_width = |uri: String| {
+            let state = state.clone();
+            async move {
+                let (response, body) = get(&state, &uri, &[]).await;
+                (response.status(), webp_width(&body))
             }
-        }
+        };
 
-        let mut config_path = String::from("./config.json");
-        if let Some(cli_config_path) = matches.opt_str("c") {
-            config_path = cli_config_path.clone();
-        }
-        match load_config(config_path) {
-            Ok(value) => {
-                unsafe { CONFIG = value; CONFIG.clone() }
-            },
-            Err(e) => panic!("[ERROR] Cannot read config file {}", e),
-        }
+        assert_eq!(status_and_width("/webp-server.jpg?variant=thumb".to_string()).await, (StatusCode::OK, Some(100)));
+        assert_eq!(status_and_width("/webp-server.jpg?variant=large".to_string()).await.0, StatusCode::BAD_REQUEST);
+        assert_eq!(status_and_width("/webp-server.jpg?width=120".to_string()).await.0, StatusCode::FORBIDDEN);
+        assert_eq!(status_and_width("/webp-server.jpg?width=120&signature=00".to_string()).await.0, StatusCode::FORBIDDEN);
+        let signature = signature::sign("secret", "/webp-server.jpg?width=120");
+        assert_eq!(status_and_width(format!("/webp-server.jpg?signature={}&width=120", signature)).await, (StatusCode::OK, Some(120)));
+        // the signature is bound to the path
+        assert_eq!(status_and_width(format!("/lossy/webp-server.jpg?signature={}&width=120", signature)).await.0, StatusCode::FORBIDDEN);
+        // untransformed images don't need one
+        assert_eq!(status_and_width("/webp-server.jpg".to_string()).await.0, StatusCode::OK);
+
+        // unsigned queries can only resize, to one of transform_sizes
+        config.signing_key = None;
+        config.transform_sizes = Some(vec![100, 300]);
+        assert!(config.validate_server().is_ok());
+        let path = Path::new("./images/webp-server.jpg");
+        let from_request = |query: &str| Transform::from_request(&config, "/webp-server.jpg", query, path).map(|transform| (transform.width, transform.height)).map_err(|(status, _)| status);
+        assert_eq!(from_request("width=120&height=50"), Ok((Some(300), Some(100))));
+        assert_eq!(from_request("width=5000"), Ok((Some(300), None)));
+        assert_eq!(from_request("crop=smart&width=90&height=90"), Ok((Some(100), Some(100))));
+        assert_eq!(from_request("variant=thumb"), Ok((Some(100), None)));
+        assert_eq!(from_request("crop=0,0,10,10"), Err(StatusCode::FORBIDDEN));
+        assert_eq!(from_request("width=100&grayscale"), Err(StatusCode::FORBIDDEN));
+        assert_eq!(from_request("width=16384"), Err(StatusCode::BAD_REQUEST));
+        assert_eq!(from_request("crop=0,0,20000,10"), Err(StatusCode::BAD_REQUEST));
+        config.transform_sizes = Some(vec![0]);
+        assert!(config.validate_server().is_err());
+        config.transform_sizes = None;
+
+        config.variants = Some([("nested".to_string(), "variant=thumb".to_string())].iter().cloned().collect());
+        assert!(config.validate_server().is_err());
+        config.variants = Some([("broken".to_string(), "rotate=45".to_string())].iter().cloned().collect());
+        assert!(config.validate_server().is_err());
     }
-}
 
-fn load_config<P: AsRef<Path>>(conf_path: P) -> Result<WebPServerConfig, Box<dyn std::error::Error>> {
-    let file = std::fs::File::open(conf_path)?;
-    let reader = BufReader::new(file);
-    let u = serde_json::from_reader(reader)?;
-    Ok(u)
-}
+    #[test]
+    fn test_focal_point() {
+        let _ = std::fs::create_dir_all("./cache");
+        let img_absolute_path = Path::new("./cache/test_focal_point.jpg");
+        let sidecar_path = "./cache/test_focal_point.jpg.focus";
+        let _ = std::fs::remove_file(sidecar_path);
+        assert_eq!(Transform::from_query("crop=smart&width=1&height=1", img_absolute_path).unwrap().focal_point, None);
 
-fn get_server_listen_options() -> SocketAddr {
-    let config = from_cli_args();
-    let host = config.host;
-    format!("{}:{}", host, config.port).parse().unwrap()
-}
+        std::fs::write(sidecar_path, r#"{"x": 0.25, "y": 0.75}"#).unwrap();
+        let transform = Transform::from_query("crop=smart&width=1&height=1", img_absolute_path).unwrap();
+        assert_eq!(transform.focal_point, Some((0.25, 0.75)));
+        assert_eq!(transform.variant_name(), "smartcrop0.2500-0.7500.w1.h1");
 
-#[cfg(test)]
-mod tests {
-    use super::*;
+        // an invalid sidecar file is ignored
+        std::fs::write(sidecar_path, r#"{"x": 1.5, "y": 0.75}"#).unwrap();
+        assert!(FocalPoint::load(img_absolute_path).is_err());
+        assert_eq!(Transform::from_query("crop=smart&width=1&height=1", img_absolute_path).unwrap().focal_point, None);
+        let _ = std::fs::remove_file(sidecar_path);
+    }
 
     #[test]
-    fn test_generate_webp_paths() {
-        let webp_paths = generate_webp_paths(&PathBuf::from("./images/webp-server.jpg"), "/webp-server.jpg", "./cache");
-        assert!(webp_paths.0.eq(&PathBuf::from(format!("./cache/webp-server.jpg.{}.webp", std::fs::metadata("./images/webp-server.jpg").unwrap().modified().unwrap().duration_since(SystemTime::UNIX_EPOCH).unwrap().as_secs()))));
-        assert!(webp_paths.1.eq(&PathBuf::from("./cache/")));
-        assert!(webp_paths.2.eq(&PathBuf::from("./images")));
+    fn test_image_palette() {
+        let _ = std::fs::create_dir_all("./cache");
+        let png_path = "./cache/test_image_palette.png";
+        image::RgbaImage::from_fn(40, 40, |x, _| if x < 30 { image::Rgba([200, 30, 30, 255]) } else { image::Rgba([30, 30, 200, 255]) }).save(png_path).unwrap();
+        let palette = ImagePalette::read(png_path).unwrap();
+        let color = Placeholder::ColorWebP.generate(png_path).unwrap();
+        let color_png = Placeholder::ColorPng.generate(png_path).unwrap();
+        let _ = std::fs::remove_file(png_path);
+
+        assert_eq!(palette.dominant_color.as_deref(), Some("#c81e1e"));
+        assert!(palette.palette.len() <= PALETTE_SIZE);
+        assert!(palette.palette.contains(&"#1e1ec8".to_string()));
+
+        let decoded = decode_webp(&color).expect("dominant color should be a valid WebP image");
+        assert_eq!(decoded.dimensions(), (1, 1));
+        assert_eq!(decoded.get_pixel(0, 0).0, [200, 30, 30, 255]);
+        let decoded = image::load_from_memory_with_format(&color_png, image::ImageFormat::Png).unwrap().to_rgba8();
+        assert_eq!(decoded.get_pixel(0, 0).0, [200, 30, 30, 255]);
     }
 
     #[test]
-    fn test_convert_mode_1() -> Result<(), io::Error> {
+    fn test_image_info() {
+        let info = ImageInfo::read("images/webp-server.jpg").unwrap();
+        let (width, height) = image::image_dimensions("images/webp-server.jpg").unwrap();
+        assert_eq!((info.width, info.height), (width, height));
+        assert!((info.aspect_ratio - width as f64 / height as f64).abs() < 1e-9);
+        assert!(!info.has_alpha);
+        assert_eq!(info.format.as_deref(), Some("jpeg"));
+        assert_eq!(info.size, std::fs::metadata("images/webp-server.jpg").unwrap().len());
+        assert_eq!(info.webp_size, None);
+
+        let _ = std::fs::create_dir_all("./cache");
+        let png_path = "./cache/test_image_info.png";
+        image::RgbaImage::from_pixel(30, 20, image::Rgba([0, 0, 0, 128])).save(png_path).unwrap();
+        let info = ImageInfo::read(png_path).unwrap();
+        let _ = std::fs::remove_file(png_path);
+        assert_eq!((info.width, info.height, info.has_alpha), (30, 20, true));
+        assert_eq!(info.format.as_deref(), Some("png"));
+        assert!((info.aspect_ratio - 1.5).abs() < 1e-9);
+    }
+
+    #[tokio::test]
+    async fn test_convert_mode_1() -> Result<(), io::Error> {
         let webp_paths = generate_webp_paths(&PathBuf::from("./images/lossless/webp-server.jpg"), "/lossless/webp-server.jpg", "./cache");
 
         // try to remove file before testing
@@ -635,7 +3586,7 @@ mod tests {
         config.lossless = Some(1);
         config.near_lossless = Some(100);
         config.quality = Some(50.0);
-        let _ = convert("images/lossless/webp-server.jpg", webp_paths.0.to_str().unwrap(), &config)?;
+        convert(&FilesystemStorage, &ConversionPool::new(1, Arc::new(Metrics::new())), "images/lossless/webp-server.jpg", &webp_paths.0, &config, &Transform::default()).await?;
         assert!(webp_paths.0.exists(),
                 "Converted WebP image should be at {}, but wasn't", webp_paths.0.display());
         assert_ne!(std::fs::metadata(&webp_paths.0).unwrap().len(), 0,
@@ -644,8 +3595,8 @@ mod tests {
         Ok(())
     }
 
-    #[test]
-    fn test_convert_mode_2() -> Result<(), io::Error> {
+    #[tokio::test]
+    async fn test_convert_mode_2() -> Result<(), io::Error> {
         let webp_paths = generate_webp_paths(&PathBuf::from("./images/nearlossless/webp-server.jpg"), "/nearlossless/webp-server.jpg", "./cache");
 
         // try to remove file before testing
@@ -661,7 +3612,7 @@ mod tests {
         config.lossless = Some(1);
         config.near_lossless = Some(50);
         config.quality = Some(40.0);
-        convert("images/nearlossless/webp-server.jpg", webp_paths.0.to_str().unwrap(), &config)?;
+        convert(&FilesystemStorage, &ConversionPool::new(1, Arc::new(Metrics::new())), "images/nearlossless/webp-server.jpg", &webp_paths.0, &config, &Transform::default()).await?;
         assert!(webp_paths.0.exists(),
                 "Converted WebP image should be at {}, but wasn't", webp_paths.0.display());
         assert_ne!(std::fs::metadata(&webp_paths.0).unwrap().len(), 0,
@@ -670,8 +3621,8 @@ mod tests {
         Ok(())
     }
 
-    #[test]
-    fn test_convert_mode_3() -> Result<(), io::Error> {
+    #[tokio::test]
+    async fn test_convert_mode_3() -> Result<(), io::Error> {
         let webp_paths = generate_webp_paths(&PathBuf::from("./images/lossy/webp-server.jpg"), "/lossy/webp-server.jpg", "./cache");
 
         // try to remove file before testing
@@ -687,7 +3638,7 @@ mod tests {
         config.lossless = Some(0);
         config.near_lossless = Some(100);
         config.quality = Some(30.0);
-        convert("images/lossy/webp-server.jpg", webp_paths.0.to_str().unwrap(), &config)?;
+        convert(&FilesystemStorage, &ConversionPool::new(1, Arc::new(Metrics::new())), "images/lossy/webp-server.jpg", &webp_paths.0, &config, &Transform::default()).await?;
         assert!(webp_paths.0.exists(),
                 "Converted WebP image should be at {}, but wasn't", webp_paths.0.display());
         assert_ne!(std::fs::metadata(&webp_paths.0).unwrap().len(), 0,
@@ -696,6 +3647,126 @@ mod tests {
         Ok(())
     }
 
+    #[test]
+    fn test_lossless_level() {
+        let image = image::RgbImage::from_fn(32, 32, |x, y| image::Rgb([(x * 8) as u8, (y * 8) as u8, ((x * y) % 251) as u8]));
+        let mut config = DirectoryLevelConfig::new();
+        config.lossless_level = Some(9);
+        assert!(config.validate().is_ok());
+
+        // lossless_level alone turns on lossless encoding
+        let encoded_data = encode(image::DynamicImage::ImageRgb8(image.clone()), &config).unwrap();
+        let original = image::DynamicImage::ImageRgb8(image.clone()).to_rgba8();
+        assert_eq!(psnr(&original, &decode_webp(&encoded_data).unwrap()), 99.0);
+
+        // while individual parameters still take precedence
+        config.lossless = Some(0);
+        config.quality = Some(10.0);
+        let encoded_data = encode(image::DynamicImage::ImageRgb8(image), &config).unwrap();
+        assert!(psnr(&original, &decode_webp(&encoded_data).unwrap()) < 99.0);
+
+        config.lossless_level = Some(10);
+        assert_eq!(config.validate().unwrap_err()[0].name, "lossless_level");
+    }
+
+    #[test]
+    fn test_encoder_errors() {
+        let mut config = DirectoryLevelConfig::new();
+        let pixels = vec![0u8; 20000 * 3];
+
+        // bypasses DirectoryLevelConfig::validate on purpose
+        config.method = Some(9);
+        let error = WebPEncoder::new(&config).unwrap().encode(&pixels, 4, 4, 12, WebPImporter::Rgb).err().unwrap();
+        assert_eq!(error.error_code, 4);
+        config.method = None;
+        config.lossless_level = Some(10);
+        assert_eq!(WebPEncoder::new(&config).err().unwrap().error_code, WebPEncodeError::LOSSLESS_PRESET);
+
+        config.lossless_level = None;
+        let encoder = WebPEncoder::new(&config).unwrap();
+        let error = encoder.encode(&pixels, 20000, 1, 20000 * 3, WebPImporter::Rgb).err().unwrap();
+        assert_eq!(error.error_code, 5);
+        let error = encoder.encode(&pixels, 200, 200, 200 * 3, WebPImporter::Rgb).err().unwrap();
+        assert_eq!(error.error_code, WebPEncodeError::BUFFER_TOO_SMALL);
+
+        let encoded_data = encoder.encode(&pixels, 100, 100, 100 * 3, WebPImporter::Rgb).unwrap();
+        assert_eq!(decode_webp(&encoded_data).unwrap().dimensions(), (100, 100));
+    }
+
+    fn assert_lossless_roundtrip<F>(image: image::DynamicImage, expected: F) where F: Fn(u32, u32) -> [u8; 4] {
+        let mut config = DirectoryLevelConfig::new();
+        config.lossless = Some(1);
+        config.exact = Some(1);
+        let color = image.color();
+        let decoded = decode_webp(&encode(image, &config).unwrap()).unwrap();
+        for (x, y, pixel) in decoded.enumerate_pixels() {
+            assert_eq!(pixel.0, expected(x, y), "{:?} at ({}, {})", color, x, y);
+        }
+    }
+
+    #[test]
+    fn test_encode_channel_order() {
+        let rgba = |x: u32, y: u32| [(x * 32) as u8, (y * 32) as u8, (255 - x * 16) as u8, (128 + y * 16) as u8];
+        let rgb = |x: u32, y: u32| { let [r, g, b, _] = rgba(x, y); [r, g, b, 255] };
+        let luma = |x: u32, _: u32| [(x * 32) as u8, (x * 32) as u8, (x * 32) as u8, 255];
+        let luma_alpha = |x: u32, y: u32| [(x * 32) as u8, (x * 32) as u8, (x * 32) as u8, (128 + y * 16) as u8];
+        let wide = |value: u8| value as u16 * 257;
+
+        assert_lossless_roundtrip(image::DynamicImage::ImageRgb8(image::ImageBuffer::from_fn(8, 8, |x, y| {
+            let [r, g, b, _] = rgba(x, y); image::Rgb([r, g, b])
+        })), rgb);
+        assert_lossless_roundtrip(image::DynamicImage::ImageRgba8(image::ImageBuffer::from_fn(8, 8, |x, y| image::Rgba(rgba(x, y)))), rgba);
+        assert_lossless_roundtrip(image::DynamicImage::ImageBgr8(image::ImageBuffer::from_fn(8, 8, |x, y| {
+            let [r, g, b, _] = rgba(x, y); image::Bgr([b, g, r])
+        })), rgb);
+        assert_lossless_roundtrip(image::DynamicImage::ImageBgra8(image::ImageBuffer::from_fn(8, 8, |x, y| {
+            let [r, g, b, a] = rgba(x, y); image::Bgra([b, g, r, a])
+        })), rgba);
+        assert_lossless_roundtrip(image::DynamicImage::ImageLuma8(image::ImageBuffer::from_fn(8, 8, |x, y| image::Luma([luma(x, y)[0]]))), luma);
+        assert_lossless_roundtrip(image::DynamicImage::ImageLumaA8(image::ImageBuffer::from_fn(8, 8, |x, y| {
+            let [l, _, _, a] = luma_alpha(x, y); image::LumaA([l, a])
+        })), luma_alpha);
+        assert_lossless_roundtrip(image::DynamicImage::ImageRgb16(image::ImageBuffer::from_fn(8, 8, |x, y| {
+            let [r, g, b, _] = rgba(x, y); image::Rgb([wide(r), wide(g), wide(b)])
+        })), rgb);
+        assert_lossless_roundtrip(image::DynamicImage::ImageRgba16(image::ImageBuffer::from_fn(8, 8, |x, y| {
+            let [r, g, b, a] = rgba(x, y); image::Rgba([wide(r), wide(g), wide(b), wide(a)])
+        })), rgba);
+        assert_lossless_roundtrip(image::DynamicImage::ImageLuma16(image::ImageBuffer::from_fn(8, 8, |x, y| image::Luma([wide(luma(x, y)[0])]))), luma);
+        assert_lossless_roundtrip(image::DynamicImage::ImageLumaA16(image::ImageBuffer::from_fn(8, 8, |x, y| {
+            let [l, _, _, a] = luma_alpha(x, y); image::LumaA([wide(l), wide(a)])
+        })), luma_alpha);
+    }
+
+    #[test]
+    fn test_samples_to_8bit() {
+        // exact values stay the same with or without dithering
+        let exact: Vec<u16> = (0..16).map(|value| value * 257 * 16).collect();
+        let expected: Vec<u8> = (0..16).map(|value| (value * 16) as u8).collect();
+        assert_eq!(samples_to_8bit(&exact, 4, 4, true, false), expected);
+        assert_eq!(samples_to_8bit(&exact, 4, 4, true, true), expected);
+
+        // about 100.5 in 8-bit, rounded down without dithering, spread evenly with dithering
+        let halfway = vec![257 * 100 + 128; 16];
+        assert!(samples_to_8bit(&halfway, 4, 1, false, false).iter().all(|&value| value == 100));
+        let dithered = samples_to_8bit(&halfway, 4, 1, false, true);
+        assert_eq!(dithered.iter().filter(|&&value| value == 100).count(), 8);
+        assert_eq!(dithered.iter().filter(|&&value| value == 101).count(), 8);
+    }
+
+    #[tokio::test]
+    async fn test_convert_failure_leaves_no_file() {
+        let webp_path = "./cache/convert-failure-test.webp";
+        let _ = std::fs::create_dir_all("./cache");
+        let _ = std::fs::remove_file(webp_path);
+
+        let mut config = DirectoryLevelConfig::new();
+        config.segments = Some(0);
+        let error = convert(&FilesystemStorage, &ConversionPool::new(1, Arc::new(Metrics::new())), "images/webp-server.jpg", Path::new(webp_path), &config, &Transform::default()).await.unwrap_err();
+        assert!(error.to_string().contains("error code 4"), "unexpected error: {}", error);
+        assert!(!PathBuf::from(webp_path).exists());
+    }
+
     fn generate_config(img_path: &str, webp_path: &str, lossless: i32, near_lossless: i32, quality: f32) -> WebPServerConfig {
         let mut config = WebPServerConfig {
             host: String::new(),
@@ -703,6 +3774,14 @@ mod tests {
             img_path: img_path.to_string(),
             webp_path: webp_path.to_string(),
             global_config: DirectoryLevelConfig::new(),
+            client_hints: None,
+            variants: None,
+            signing_key: None,
+            transform_sizes: None,
+            origin: None,
+            s3: None,
+            hot_cache: None,
+            metrics: None,
         };
         config.global_config.lossless = Some(lossless);
         config.global_config.near_lossless = Some(near_lossless);
@@ -710,48 +3789,312 @@ mod tests {
         config
     }
 
+    /// A temporary directory for the images and cache of one test, removed when dropped
+    struct TestDir(PathBuf);
+
+    impl TestDir {
+        fn new(name: &str) -> TestDir {
+            let dir = std::env::temp_dir().join(format!("webp-server-{}-test-{}", name, std::process::id()));
+            let _ = std::fs::remove_dir_all(&dir);
+            std::fs::create_dir_all(dir.join("images")).unwrap();
+            TestDir(dir)
+        }
+
+        fn img_path(&self) -> String {
+            self.0.join("images").to_str().unwrap().to_string()
+        }
+
+        fn config(&self) -> WebPServerConfig {
+            generate_config(&self.img_path(), self.0.join("cache").to_str().unwrap(), 0, 0, 75.0)
+        }
+    }
+
+    impl Drop for TestDir {
+        fn drop(&mut self) {
+            let _ = std::fs::remove_dir_all(&self.0);
+        }
+    }
+
+    fn test_state(config: WebPServerConfig) -> Arc<AppState> {
+        Arc::new(AppState::new(String::new(), config, PrefetchConfig { enabled: false, jobs: 1 }))
+    }
+
+    /// Sends a GET request for `uri` with `headers`, returns the response along with its body
+    async fn get(state: &Arc<AppState>, uri: &str, headers: &[(&str, &str)]) -> (Response<()>, Bytes) {
+        let mut request = Request::get(uri);
+        for (name, value) in headers {
+            request = request.header(*name, *value);
+        }
+        let (parts, body) = webp_services(state.clone(), request.body(Body::empty()).unwrap()).await.unwrap().into_parts();
+        (Response::from_parts(parts, ()), hyper::body::to_bytes(body).await.unwrap())
+    }
+
+    fn webp_width(body: &[u8]) -> Option<u32> {
+        decode_webp(body).map(|image| image.width())
+    }
+
+    /// Waits until files are written in the next second, as cached WebP images are named after
+    /// the modification time of the original in seconds
+    async fn next_second() {
+        let now = SystemTime::now().duration_since(SystemTime::UNIX_EPOCH).unwrap();
+        tokio::time::delay_for(Duration::from_nanos(1_000_000_000 - u64::from(now.subsec_nanos())) + Duration::from_millis(20)).await;
+    }
+
+    #[tokio::test]
+    async fn test_reload_keeps_old_config_if_invalid() {
+        let test_dir = TestDir::new("reload");
+        let dir = &test_dir.0;
+        let config_path = dir.join("config.json");
+        let images = std::fs::canonicalize("./images").unwrap();
+        let write_config = |port: u16, img_path: &Path, quality: u32| {
+            let config = serde_json::json!({"port": port, "img_path": img_path, "webp_path": dir.join("cache"), "global_config": {"quality": quality}});
+            std::fs::write(&config_path, config.to_string()).unwrap();
+        };
+        write_config(3334, &images, 80);
+        let state = AppState::new(config_path.to_str().unwrap().to_string(), load_config(&config_path).unwrap(), PrefetchConfig { enabled: false, jobs: 1 });
+        let addr = state.config().listen_addr().unwrap();
+
+        // same address, swapped in without rebinding
+        write_config(3334, &images, 60);
+        assert!(reload(&state, addr).is_none());
+        assert_eq!(state.config().global_config.quality, Some(60.0));
+
+        // invalid or unreadable config files are ignored
+        write_config(3334, &dir.join("no-such-images"), 40);
+        assert!(reload(&state, addr).is_none());
+        std::fs::write(&config_path, r#"{"port": 3336, "#).unwrap();
+        assert!(reload(&state, addr).is_none());
+        assert_eq!(state.config().global_config.quality, Some(60.0));
+
+        // an address that cannot be bound keeps the old config as well
+        let taken = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        write_config(taken.local_addr().unwrap().port(), &images, 40);
+        assert!(reload(&state, addr).is_none());
+        assert_eq!(state.config().port, 3334);
+
+        // a new address is bound before the config is swapped in
+        write_config(0, &images, 40);
+        let (new_addr, _) = reload(&state, addr).unwrap();
+        assert_eq!(new_addr.port(), 0);
+        assert_eq!((state.config().port, state.config().global_config.quality), (0, Some(40.0)));
+    }
+
+    #[test]
+    fn test_check_config() {
+        let test_dir = TestDir::new("check-config");
+        let dir = &test_dir.0;
+        let img_path = dir.join("images");
+        std::fs::create_dir_all(img_path.join("path/to")).unwrap();
+        let config_path = dir.join("config.json");
+        let config = serde_json::json!({"img_path": img_path, "webp_path": dir.join("cache"), "global_config": {"quality": 80}});
+        std::fs::write(&config_path, config.to_string()).unwrap();
+        std::fs::write(img_path.join("path/.webp-conf"), r#"{"qmin": 60}"#).unwrap();
+        assert!(check_config(config_path.to_str().unwrap()));
+
+        // valid on its own, not along with the qmin above it
+        std::fs::write(img_path.join("path/to/.webp-conf"), r#"{"qmax": 40}"#).unwrap();
+        assert!(!check_config(config_path.to_str().unwrap()));
+        let directory = img_path.join("path/to");
+        let error = DirectoryLevelConfig::detect(img_path.to_str().unwrap(), directory.to_str().unwrap(), &DirectoryLevelConfig::new()).unwrap_err();
+        assert!(error.contains(&img_path.join("path/.webp-conf").display().to_string()));
+        assert!(error.contains(&img_path.join("path/to/.webp-conf").display().to_string()));
+
+        // starting over from libwebp defaults there is no qmin
+        std::fs::write(img_path.join("path/to/.webp-conf"), r#"{"inherit": false, "qmax": 40}"#).unwrap();
+        assert!(check_config(config_path.to_str().unwrap()));
+    }
+
+    #[test]
+    fn test_validate_directory_level_config() {
+        let mut config = DirectoryLevelConfig::new();
+        config.quality = Some(75.0);
+        config.method = Some(6);
+        config.preset = Some("photo".to_string());
+        assert!(config.validate().is_ok());
+
+        config.method = Some(9);
+        config.quality = Some(101.0);
+        config.image_hint = Some("cartoon".to_string());
+        let invalid_fields = config.validate().unwrap_err();
+        let names: Vec<&str> = invalid_fields.iter().map(|invalid| invalid.name).collect();
+        assert_eq!(names, vec!["quality", "method", "image_hint"]);
+
+        let mut config = DirectoryLevelConfig::new();
+        config.qmin = Some(20);
+        config.qmax = Some(80);
+        assert!(config.validate().is_ok());
+        config.qmin = Some(90);
+        assert_eq!(config.validate().unwrap_err()[0].name, "qmin");
+        config.qmin = Some(-1);
+        assert_eq!(config.validate().unwrap_err()[0].name, "qmin");
+    }
+
+    #[test]
+    fn test_detect_invalid_directory_level_config() {
+        let directory_path = "./detect-test-images";
+        let _ = std::fs::create_dir_all(directory_path);
+        let global_config = DirectoryLevelConfig::new();
+
+        std::fs::write(format!("{}/.webp-conf", directory_path), "{\n  \"quality\": 40,\n  \"sns_strength\": 200\n}\n").unwrap();
+        let error = DirectoryLevelConfig::detect(directory_path, directory_path, &global_config).unwrap_err();
+        assert!(error.contains(".webp-conf:3: sns_strength"), "unexpected error: {}", error);
+
+        std::fs::write(format!("{}/.webp-conf", directory_path), "{\n  \"quality\": 40,\n  \"qualty\": 50\n}\n").unwrap();
+        let error = DirectoryLevelConfig::detect(directory_path, directory_path, &global_config).unwrap_err();
+        assert!(error.contains(".webp-conf:3: unknown field `qualty`"), "unexpected error: {}", error);
+
+        std::fs::write(format!("{}/.webp-conf", directory_path), "{\"quality\": 40}").unwrap();
+        assert_eq!(DirectoryLevelConfig::detect(directory_path, directory_path, &global_config).unwrap().quality, Some(40.0));
+
+        let _ = std::fs::remove_dir_all(directory_path);
+        assert_eq!(DirectoryLevelConfig::detect(directory_path, directory_path, &global_config).unwrap().quality, None);
+    }
+
+    #[tokio::test]
+    async fn test_invalid_directory_level_config_response() {
+        let dir = TestDir::new("invalid-directory-level-config-response");
+        let img_path = dir.img_path();
+        image::RgbImage::from_pixel(20, 10, image::Rgb([200, 0, 0])).save(format!("{}/photo.png", img_path)).unwrap();
+        // a typo must not send the original image, which the watermark was meant to cover
+        std::fs::write(format!("{}/.webp-conf", img_path), r#"{"watermark": {"image": "./images/webp-server.jpg"}, "qualty": 50}"#).unwrap();
+        let state = test_state(dir.config());
+        for uri in &["/photo.png", "/photo.png?info", "/photo.png?blurhash", "/photo.png?lqip", "/photo.png?dominant_color", "/photo.png?palette"] {
+            assert_eq!(get(&state, uri, &[]).await.0.status(), StatusCode::INTERNAL_SERVER_ERROR, "{}", uri);
+        }
+        let (response, _) = get(&state, "/photo.png", &[("User-Agent", "Version/14.0 Safari/605.1.15")]).await;
+        assert_eq!(response.status(), StatusCode::INTERNAL_SERVER_ERROR);
+    }
+
+    #[test]
+    fn test_detect_cascading_directory_level_config() {
+        let img_path = "./cascade-test-images";
+        let _ = std::fs::remove_dir_all(img_path);
+        std::fs::create_dir_all(format!("{}/photos/raw/thumbnails", img_path)).unwrap();
+        std::fs::write(format!("{}/.webp-conf", img_path), r#"{"method": 4}"#).unwrap();
+        std::fs::write(format!("{}/photos/.webp-conf", img_path), r#"{"quality": 40}"#).unwrap();
+        std::fs::write(format!("{}/photos/raw/.webp-conf", img_path), r#"{"inherit": false, "lossless": 1}"#).unwrap();
+
+        let mut global_config = DirectoryLevelConfig::new();
+        global_config.quality = Some(80.0);
+        global_config.use_sharp_yuv = Some(1);
+
+        let root = DirectoryLevelConfig::detect(img_path, img_path, &global_config).unwrap();
+        assert_eq!((root.quality, root.method, root.use_sharp_yuv), (Some(80.0), Some(4), Some(1)));
+
+        let photos = DirectoryLevelConfig::detect(img_path, &format!("{}/photos", img_path), &global_config).unwrap();
+        assert_eq!((photos.quality, photos.method, photos.use_sharp_yuv), (Some(40.0), Some(4), Some(1)));
+
+        let thumbnails = DirectoryLevelConfig::detect(img_path, &format!("{}/photos/raw/thumbnails", img_path), &global_config).unwrap();
+        assert_eq!((thumbnails.lossless, thumbnails.quality, thumbnails.method, thumbnails.use_sharp_yuv), (Some(1), None, None, None));
+
+        let _ = std::fs::remove_dir_all(img_path);
+    }
+
+    #[test]
+    fn test_select_encoding_rules() {
+        let config: DirectoryLevelConfig = serde_json::from_str(r#"{
+            "quality": 80,
+            "rules": [
+                { "match": { "pattern": "*.png" }, "config": { "lossless": 1 } },
+                { "match": { "format": "jpg" }, "config": { "lossless": 0, "quality": 75 } },
+                { "match": { "min_width": 8, "has_alpha": true }, "config": { "alpha_quality": 50 } }
+            ]
+        }"#).unwrap();
+        assert!(config.validate().is_ok());
+
+        let screenshot = config.select("screenshot.png", Some(image::ImageFormat::Png), &image::DynamicImage::new_rgba8(16, 16));
+        assert_eq!((screenshot.lossless, screenshot.quality, screenshot.alpha_quality), (Some(1), Some(80.0), Some(50)));
+        assert!(screenshot.rules.is_none());
+
+        let icon = config.select("icon.png", Some(image::ImageFormat::Png), &image::DynamicImage::new_rgba8(4, 4));
+        assert_eq!((icon.lossless, icon.alpha_quality), (Some(1), None));
+
+        let photo = config.select("photo.jpg", Some(image::ImageFormat::Jpeg), &image::DynamicImage::new_rgb8(16, 16));
+        assert_eq!((photo.lossless, photo.quality, photo.alpha_quality), (Some(0), Some(75.0), None));
+
+        let invalid: DirectoryLevelConfig = serde_json::from_str(r#"{
+            "rules": [
+                { "match": { "format": "psd" }, "config": { "method": 7 } }
+            ]
+        }"#).unwrap();
+        assert_eq!(invalid.validate().unwrap_err().len(), 2);
+    }
+
+    #[test]
+    fn test_auto_lossless() -> Result<(), io::Error> {
+        let mut config = DirectoryLevelConfig::new();
+        config.auto_lossless = Some(1);
+        config.quality = Some(75.0);
+
+        // a chart-like image with few colors goes lossless and stays pixel exact
+        let chart = image::RgbImage::from_fn(64, 64, |x, y| if (x / 8 + y / 8) % 2 == 0 { image::Rgb([255, 255, 255]) } else { image::Rgb([0, 0, 200]) });
+        let (encoded_data, lossless) = encode_auto_lossless("chart.png", Some(image::ImageFormat::Png), image::DynamicImage::ImageRgb8(chart.clone()), &config)?;
+        assert!(lossless);
+        assert_eq!(psnr(&image::DynamicImage::ImageRgb8(chart).to_rgba8(), &decode_webp(&encoded_data).unwrap()), 99.0);
+
+        // a photo-like image with lots of colors, lossy cannot reach 99 dB
+        let photo = image::RgbImage::from_fn(64, 64, |x, y| image::Rgb([(x * 4) as u8, (y * 4) as u8, ((x * y) % 251) as u8]));
+        config.auto_lossless_min_psnr = Some(99.0);
+        let (_, lossless) = encode_auto_lossless("photo.png", Some(image::ImageFormat::Png), image::DynamicImage::ImageRgb8(photo.clone()), &config)?;
+        assert!(lossless);
+
+        // JPEG sources are always lossy
+        let (_, lossless) = encode_auto_lossless("photo.jpg", Some(image::ImageFormat::Jpeg), image::DynamicImage::ImageRgb8(photo), &config)?;
+        assert!(!lossless);
+        Ok(())
+    }
+
     #[test]
-    fn test_prefetch() -> Result<(), io::Error> {
+    fn test_quality_search() -> Result<(), io::Error> {
+        let photo = image::RgbImage::from_fn(64, 64, |x, y| image::Rgb([(x * 4) as u8, (y * 4) as u8, ((x * y) % 251) as u8]));
+        let original = image::DynamicImage::ImageRgb8(photo.clone()).to_rgba8();
+        assert_eq!(ssim(&original, &original), 1.0);
+
+        let mut config = DirectoryLevelConfig::new();
+        config.search_target_ssim = Some(0.98);
+        let high_quality = encode_quality_search("photo.png", image::DynamicImage::ImageRgb8(photo.clone()), &config)?;
+        assert!(ssim(&original, &decode_webp(&high_quality).unwrap()) >= 0.98);
+
+        config.search_target_ssim = Some(0.8);
+        let low_quality = encode_quality_search("photo.png", image::DynamicImage::ImageRgb8(photo), &config)?;
+        assert!(ssim(&original, &decode_webp(&low_quality).unwrap()) >= 0.8);
+        assert!(low_quality.len() <= high_quality.len());
+
+        config.search_target_psnr = Some(40.0);
+        assert!(config.validate().is_err());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_prefetch() -> Result<(), io::Error> {
         // remove webp cache directory
         let prefetch_cache_path = "./prefetch-cache";
         let _ = std::fs::remove_dir_all(prefetch_cache_path);
         assert!(!PathBuf::from(prefetch_cache_path).exists());
 
         // enable prefetch
-        unsafe { PREFETCH.enabled = true; };
-
-        let done: std::sync::Arc<std::sync::atomic::AtomicBool> = std::sync::Arc::new(std::sync::atomic::AtomicBool::new(false));
-        let done_copy = std::sync::Arc::clone(&done);
-        prefetch_if_requested(generate_config("./images", prefetch_cache_path, 0, 100, 40.0), false, move ||{
-            let prefetch_images = vec![
-                "./images/webp-server.jpg",
-                "./images/lossy/webp-server.jpg",
-                "./images/nearlossless/webp-server.jpg",
-                "./images/lossless/webp-server.jpg",
-            ];
-
-            for prefetch_image in prefetch_images {
-                let webp_paths = generate_webp_paths(&PathBuf::from(prefetch_image), &prefetch_image[8..], "./prefetch-cache");
-                if !webp_paths.0.exists() {
-                    done_copy.store(true, std::sync::atomic::Ordering::Relaxed);
-                }
-                assert!(webp_paths.0.exists(),
-                        "Converted WebP image should be at {}, but wasn't", webp_paths.0.display());
-                assert_ne!(std::fs::metadata(&webp_paths.0).unwrap().len(), 0,
-                           "Size of converted WebP image is 0, which suggested failed");
-            }
+        let prefetch = PrefetchConfig { enabled: true, jobs: 2 };
 
-            let _ = std::fs::remove_dir_all(prefetch_cache_path);
-            done_copy.store(true, std::sync::atomic::Ordering::Relaxed);
+        let (done_tx, done_rx) = oneshot::channel::<()>();
+        prefetch_if_requested(generate_config("./images", prefetch_cache_path, 0, 100, 40.0), &prefetch, Arc::new(ConversionPool::new(2, Arc::new(Metrics::new()))), false, move || {
+            let _ = done_tx.send(());
         });
+        done_rx.await.unwrap();
 
-        loop {
-            let ticker = tick(Duration::from_micros(500));
-            ticker.recv().unwrap();
-            if done.load(std::sync::atomic::Ordering::Relaxed) {
-                break;
-            }
+        let prefetch_images = vec![
+            "./images/webp-server.jpg",
+            "./images/lossy/webp-server.jpg",
+            "./images/nearlossless/webp-server.jpg",
+            "./images/lossless/webp-server.jpg",
+        ];
+        for prefetch_image in prefetch_images {
+            let webp_paths = generate_webp_paths(&PathBuf::from(prefetch_image), &prefetch_image[8..], "./prefetch-cache");
+            assert!(webp_paths.0.exists(),
+                    "Converted WebP image should be at {}, but wasn't", webp_paths.0.display());
+            assert_ne!(std::fs::metadata(&webp_paths.0).unwrap().len(), 0,
+                       "Size of converted WebP image is 0, which suggested failed");
         }
+        let _ = std::fs::remove_dir_all(prefetch_cache_path);
         Ok(())
     }
 }
@@ -0,0 +1,189 @@
+//! Counters and gauges exposed at `/metrics` in the Prometheus text format
+
+use std::collections::BTreeMap;
+use std::fmt::Write;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Mutex;
+use std::time::Instant;
+
+use crate::storage::HotCacheStats;
+
+/// Upper bounds of the buckets of conversion durations, in seconds
+const CONVERSION_DURATION_BUCKETS: [f64; 10] = [0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0];
+
+pub struct Metrics {
+    // by status code and format of the response
+    requests: Mutex<BTreeMap<(u16, String), u64>>,
+    cache_hits: AtomicU64,
+    cache_misses: AtomicU64,
+    // cumulative, like they are exposed
+    conversion_buckets: [AtomicU64; CONVERSION_DURATION_BUCKETS.len()],
+    conversion_count: AtomicU64,
+    conversion_microseconds: AtomicU64,
+    // waiting for a worker or in progress
+    queued_conversions: AtomicU64,
+    bytes_saved: AtomicU64,
+    prefetch_files: AtomicU64,
+    prefetch_done_files: AtomicU64,
+}
+
+/// Counts in the queue of conversions until dropped
+pub struct QueuedConversion<'a> {
+    metrics: &'a Metrics,
+}
+
+impl Drop for QueuedConversion<'_> {
+    fn drop(&mut self) {
+        self.metrics.queued_conversions.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+/// Its duration is recorded when dropped
+pub struct Conversion<'a> {
+    metrics: &'a Metrics,
+    started_at: Instant,
+}
+
+impl Drop for Conversion<'_> {
+    fn drop(&mut self) {
+        let seconds = self.started_at.elapsed().as_secs_f64();
+        for (bucket, upper_bound) in self.metrics.conversion_buckets.iter().zip(CONVERSION_DURATION_BUCKETS.iter()) {
+            if seconds <= *upper_bound {
+                bucket.fetch_add(1, Ordering::Relaxed);
+            }
+        }
+        self.metrics.conversion_count.fetch_add(1, Ordering::Relaxed);
+        self.metrics.conversion_microseconds.fetch_add((seconds * 1e6) as u64, Ordering::Relaxed);
+    }
+}
+
+impl Metrics {
+    pub fn new() -> Metrics {
+        Metrics {
+            requests: Mutex::new(BTreeMap::new()),
+            cache_hits: AtomicU64::new(0),
+            cache_misses: AtomicU64::new(0),
+            conversion_buckets: Default::default(),
+            conversion_count: AtomicU64::new(0),
+            conversion_microseconds: AtomicU64::new(0),
+            queued_conversions: AtomicU64::new(0),
+            bytes_saved: AtomicU64::new(0),
+            prefetch_files: AtomicU64::new(0),
+            prefetch_done_files: AtomicU64::new(0),
+        }
+    }
+
+    pub fn request(&self, status: u16, format: &str) {
+        *self.requests.lock().unwrap().entry((status, format.to_string())).or_insert(0) += 1;
+    }
+
+    pub fn cache_hit(&self) {
+        self.cache_hits.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn cache_miss(&self) {
+        self.cache_misses.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// From the moment a conversion is asked for, before it gets a worker
+    pub fn queued_conversion(&self) -> QueuedConversion<'_> {
+        self.queued_conversions.fetch_add(1, Ordering::Relaxed);
+        QueuedConversion { metrics: self }
+    }
+
+    pub fn conversion(&self) -> Conversion<'_> {
+        Conversion { metrics: self, started_at: Instant::now() }
+    }
+
+    /// Size of the original image minus size of the WebP image sent instead, if that is smaller
+    pub fn bytes_saved(&self, original_size: u64, webp_size: u64) {
+        self.bytes_saved.fetch_add(original_size.saturating_sub(webp_size), Ordering::Relaxed);
+    }
+
+    pub fn prefetch_progress(&self, done_files: usize, files: usize) {
+        self.prefetch_done_files.store(done_files as u64, Ordering::Relaxed);
+        self.prefetch_files.store(files as u64, Ordering::Relaxed);
+    }
+
+    pub fn render(&self, hot_cache: Option<HotCacheStats>) -> String {
+        let mut output = String::new();
+        let mut metric = |name: &str, kind: &str, help: &str, samples: Vec<(String, String)>| {
+            let _ = writeln!(output, "# HELP {} {}\n# TYPE {} {}", name, help, name, kind);
+            for (labels, value) in samples {
+                let _ = writeln!(output, "{}{} {}", name, labels, value);
+            }
+        };
+        let value = |counter: &AtomicU64| vec![(String::new(), counter.load(Ordering::Relaxed).to_string())];
+
+        metric("webp_server_requests_total", "counter", "Requests by status code and format of the response",
+               self.requests.lock().unwrap().iter()
+                   .map(|((status, format), count)| (format!("{{status=\"{}\",format=\"{}\"}}", status, format), count.to_string()))
+                   .collect());
+        metric("webp_server_cache_hits_total", "counter", "Converted images and variants found in cache", value(&self.cache_hits));
+        metric("webp_server_cache_misses_total", "counter", "Converted images and variants generated because they were not in cache", value(&self.cache_misses));
+
+        let count = self.conversion_count.load(Ordering::Relaxed);
+        let mut buckets: Vec<(String, String)> = self.conversion_buckets.iter().zip(CONVERSION_DURATION_BUCKETS.iter())
+            .map(|(bucket, upper_bound)| (format!("_bucket{{le=\"{}\"}}", upper_bound), bucket.load(Ordering::Relaxed).to_string()))
+            .collect();
+        buckets.push(("_bucket{le=\"+Inf\"}".to_string(), count.to_string()));
+        buckets.push(("_sum".to_string(), (self.conversion_microseconds.load(Ordering::Relaxed) as f64 / 1e6).to_string()));
+        buckets.push(("_count".to_string(), count.to_string()));
+        metric("webp_server_conversion_duration_seconds", "histogram", "Time spent decoding, transforming and encoding images", buckets);
+        metric("webp_server_conversion_queue_depth", "gauge", "Conversions waiting for a worker or in progress", value(&self.queued_conversions));
+
+        metric("webp_server_bytes_saved_total", "counter", "Bytes saved by sending WebP images instead of the original ones", value(&self.bytes_saved));
+        metric("webp_server_prefetch_files", "gauge", "Files found by prefetch", value(&self.prefetch_files));
+        metric("webp_server_prefetch_done_files", "gauge", "Files handled by prefetch", value(&self.prefetch_done_files));
+
+        if let Some(hot_cache) = hot_cache {
+            let value = |value: u64| vec![(String::new(), value.to_string())];
+            metric("webp_server_hot_cache_hits_total", "counter", "Files served from memory", value(hot_cache.hits));
+            metric("webp_server_hot_cache_misses_total", "counter", "Files not in memory", value(hot_cache.misses));
+            metric("webp_server_hot_cache_files", "gauge", "Files in memory", value(hot_cache.entries as u64));
+            metric("webp_server_hot_cache_bytes", "gauge", "Size of the files in memory", value(hot_cache.size as u64));
+        }
+        output
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_render() {
+        let metrics = Metrics::new();
+        metrics.request(200, "webp");
+        metrics.request(200, "webp");
+        metrics.request(404, "plain");
+        metrics.cache_hit();
+        metrics.bytes_saved(1000, 400);
+        metrics.bytes_saved(400, 1000);
+        metrics.prefetch_progress(3, 10);
+        {
+            let _queued = metrics.queued_conversion();
+            let _queued_too = metrics.queued_conversion();
+            assert_eq!(metrics.queued_conversions.load(Ordering::Relaxed), 2);
+            let _conversion = metrics.conversion();
+        }
+
+        let output = metrics.render(None);
+        assert!(output.contains("# TYPE webp_server_requests_total counter\n"));
+        assert!(output.contains("webp_server_requests_total{status=\"200\",format=\"webp\"} 2\n"));
+        assert!(output.contains("webp_server_requests_total{status=\"404\",format=\"plain\"} 1\n"));
+        assert!(output.contains("webp_server_cache_hits_total 1\n"));
+        assert!(output.contains("webp_server_cache_misses_total 0\n"));
+        assert!(output.contains("webp_server_conversion_duration_seconds_bucket{le=\"10\"} 1\n"));
+        assert!(output.contains("webp_server_conversion_duration_seconds_bucket{le=\"+Inf\"} 1\n"));
+        assert!(output.contains("webp_server_conversion_duration_seconds_count 1\n"));
+        assert!(output.contains("webp_server_conversion_queue_depth 0\n"));
+        assert!(output.contains("webp_server_bytes_saved_total 600\n"));
+        assert!(output.contains("webp_server_prefetch_done_files 3\n"));
+        assert!(!output.contains("hot_cache"));
+
+        let output = metrics.render(Some(HotCacheStats { hits: 5, misses: 2, entries: 1, size: 100 }));
+        assert!(output.contains("webp_server_hot_cache_hits_total 5\n"));
+        assert!(output.contains("webp_server_hot_cache_bytes 100\n"));
+    }
+}
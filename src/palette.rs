@@ -0,0 +1,154 @@
+//! Palette extraction with median cut, and the dominant color from a color histogram
+
+use image::RgbaImage;
+
+/// Pixels with alpha below this are left out, so that transparent areas don't show up in the palette
+const MIN_ALPHA: u8 = 128;
+/// Bits kept of each channel for the histogram, so that shades of one color fall into the same bin
+const HISTOGRAM_BITS: u32 = 3;
+
+/// A color of the palette and the number of pixels it represents
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Swatch {
+    pub color: [u8; 3],
+    pub population: usize,
+}
+
+impl Swatch {
+    /// `#rrggbb`
+    pub fn hex(&self) -> String {
+        format!("#{:02x}{:02x}{:02x}", self.color[0], self.color[1], self.color[2])
+    }
+}
+
+/// Extracts up to `max_colors` colors from the image, most common first
+pub fn extract(image: &RgbaImage, max_colors: usize) -> Vec<Swatch> {
+    let pixels: Vec<[u8; 3]> = image.pixels()
+        .filter(|pixel| pixel.0[3] >= MIN_ALPHA)
+        .map(|pixel| [pixel.0[0], pixel.0[1], pixel.0[2]])
+        .collect();
+    if pixels.is_empty() || max_colors == 0 {
+        return Vec::new();
+    }
+
+    // keep splitting the box with the widest channel at its median
+    let mut boxes = vec![pixels];
+    while boxes.len() < max_colors {
+        let (index, channel, range) = boxes.iter().enumerate()
+            .map(|(index, pixels)| {
+                let (channel, range) = widest_channel(pixels);
+                (index, channel, range)
+            })
+            .max_by_key(|&(_, _, range)| range)
+            .unwrap();
+        if range == 0 {
+            break;
+        }
+        let mut lower = boxes.swap_remove(index);
+        lower.sort_unstable_by_key(|pixel| pixel[channel]);
+        // split next to the median without separating equal values, which would end up as duplicate colors
+        let median = lower[lower.len() / 2][channel];
+        let split = match lower.partition_point(|pixel| pixel[channel] < median) {
+            0 => lower.partition_point(|pixel| pixel[channel] <= median),
+            split => split,
+        };
+        let upper = lower.split_off(split);
+        boxes.push(lower);
+        boxes.push(upper);
+    }
+
+    let mut swatches: Vec<Swatch> = boxes.iter().map(|pixels| average(pixels)).collect();
+    swatches.sort_by_key(|swatch| std::cmp::Reverse(swatch.population));
+    swatches
+}
+
+/// The most common color, as the average of the most populated bin of a coarse color histogram.
+/// Unlike the boxes of median cut, which hold about as many pixels each, bins have a fixed size
+/// so the largest one is the color that covers most of the image
+pub fn dominant(image: &RgbaImage) -> Option<Swatch> {
+    let shift = 8 - HISTOGRAM_BITS;
+    let mut bins = vec![([0usize; 3], 0usize); 1 << (3 * HISTOGRAM_BITS)];
+    for pixel in image.pixels().filter(|pixel| pixel.0[3] >= MIN_ALPHA) {
+        let [r, g, b, _] = pixel.0;
+        let index = ((r >> shift) as usize) << (2 * HISTOGRAM_BITS) | ((g >> shift) as usize) << HISTOGRAM_BITS | (b >> shift) as usize;
+        let (sum, population) = &mut bins[index];
+        for (sum, value) in sum.iter_mut().zip([r, g, b].iter()) {
+            *sum += *value as usize;
+        }
+        *population += 1;
+    }
+
+    let (sum, population) = bins.into_iter().filter(|&(_, population)| population > 0).max_by_key(|&(_, population)| population)?;
+    let channel_average = |channel: usize| ((sum[channel] + population / 2) / population) as u8;
+    Some(Swatch {
+        color: [channel_average(0), channel_average(1), channel_average(2)],
+        population,
+    })
+}
+
+fn widest_channel(pixels: &[[u8; 3]]) -> (usize, u8) {
+    (0..3).map(|channel| {
+        let min = pixels.iter().map(|pixel| pixel[channel]).min().unwrap_or(0);
+        let max = pixels.iter().map(|pixel| pixel[channel]).max().unwrap_or(0);
+        (channel, max - min)
+    }).max_by_key(|&(_, range)| range).unwrap()
+}
+
+fn average(pixels: &[[u8; 3]]) -> Swatch {
+    let mut sum = [0usize; 3];
+    for pixel in pixels {
+        for channel in 0..3 {
+            sum[channel] += pixel[channel] as usize;
+        }
+    }
+    let population = pixels.len();
+    let channel_average = |channel: usize| ((sum[channel] + population / 2) / population) as u8;
+    Swatch {
+        color: [channel_average(0), channel_average(1), channel_average(2)],
+        population,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_extract() {
+        // 3/4 red, 1/4 blue, and a transparent row that must be ignored
+        let image = RgbaImage::from_fn(4, 5, |x, y| match (x, y) {
+            (_, 4) => image::Rgba([0, 255, 0, 0]),
+            (3, _) => image::Rgba([0, 0, 255, 255]),
+            _ => image::Rgba([255, 0, 0, 255]),
+        });
+        let swatches = extract(&image, 5);
+        assert_eq!(swatches.len(), 2);
+        assert_eq!(swatches[0], Swatch { color: [255, 0, 0], population: 12 });
+        assert_eq!(swatches[1], Swatch { color: [0, 0, 255], population: 4 });
+        assert_eq!(swatches[0].hex(), "#ff0000");
+
+        assert_eq!(extract(&image, 1), vec![Swatch { color: [191, 0, 64], population: 16 }]);
+        assert!(extract(&RgbaImage::new(4, 4), 5).is_empty());
+    }
+
+    #[test]
+    fn test_dominant() {
+        // 40% of one gray, the rest in many different colors that median cut spreads its boxes over
+        let image = RgbaImage::from_fn(100, 10, |x, y| match x {
+            0..=39 => image::Rgba([128, 128, 128, 255]),
+            _ => image::Rgba([(x * 7 % 96) as u8, (x * 13 + y * 29) as u8, (y * 50) as u8, 255]),
+        });
+        assert_eq!(dominant(&image), Some(Swatch { color: [128, 128, 128], population: 400 }));
+
+        // shades of a color count together
+        let image = RgbaImage::from_fn(10, 10, |x, y| match (x, y) {
+            (0..=5, _) => image::Rgba([200 + x as u8, 30, 30, 255]),
+            (_, 0..=3) => image::Rgba([30, 30, 200, 0]),
+            _ => image::Rgba([30, 30, 200, 255]),
+        });
+        let swatch = dominant(&image).unwrap();
+        assert_eq!(swatch.population, 60);
+        assert_eq!(swatch.hex(), "#cb1e1e");
+        assert_eq!(dominant(&RgbaImage::new(4, 4)), None);
+    }
+}
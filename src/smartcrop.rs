@@ -0,0 +1,98 @@
+//! Picks the most interesting region of an image for a given aspect ratio, by edge density
+
+use image::{DynamicImage, GenericImageView};
+
+/// Images are analysed at this size at most, edges survive downscaling well enough
+const ANALYSIS_SIZE: u32 = 256;
+
+/// Size of the largest region in a `width` x `height` image with the aspect ratio of `target_width` : `target_height`
+pub fn crop_size(width: u32, height: u32, target_width: u32, target_height: u32) -> (u32, u32) {
+    let (width64, height64, target_width64, target_height64) = (width as u64, height as u64, target_width as u64, target_height as u64);
+    if width64 * target_height64 > height64 * target_width64 {
+        let crop_width = (height64 * target_width64 + target_height64 / 2) / target_height64;
+        (crop_width.clamp(1, width64) as u32, height)
+    } else {
+        let crop_height = (width64 * target_height64 + target_width64 / 2) / target_width64;
+        (width, crop_height.clamp(1, height64) as u32)
+    }
+}
+
+/// Top left corner of the `crop_width` x `crop_height` region with the most edges in it,
+/// ties are broken in favour of the centre of the image
+pub fn find(image: &DynamicImage, crop_width: u32, crop_height: u32) -> (u32, u32) {
+    let (width, height) = image.dimensions();
+    let horizontal = crop_width < width;
+    if (horizontal && crop_height < height) || (crop_width >= width && crop_height >= height) {
+        // only one axis is ever free for a crop from `crop_size`, centre anything else
+        return ((width - crop_width.min(width)) / 2, (height - crop_height.min(height)) / 2);
+    }
+
+    let luma = image.thumbnail(ANALYSIS_SIZE, ANALYSIS_SIZE).to_luma8();
+    let (analysis_width, analysis_height) = luma.dimensions();
+    let (length, crop_length, analysis_length) = if horizontal {
+        (width, crop_width, analysis_width)
+    } else {
+        (height, crop_height, analysis_height)
+    };
+
+    // edge energy of each column, or each row for vertical crops
+    let mut energy = vec![0u64; analysis_length as usize];
+    for y in 0..analysis_height {
+        for x in 0..analysis_width {
+            let pixel = luma.get_pixel(x, y).0[0] as i32;
+            let right = luma.get_pixel((x + 1).min(analysis_width - 1), y).0[0] as i32;
+            let below = luma.get_pixel(x, (y + 1).min(analysis_height - 1)).0[0] as i32;
+            let edge = ((right - pixel).abs() + (below - pixel).abs()) as u64;
+            energy[if horizontal { x } else { y } as usize] += edge;
+        }
+    }
+
+    let window = ((crop_length as u64 * analysis_length as u64 / length as u64) as usize).clamp(1, energy.len());
+    let centre = (energy.len() - window) / 2;
+    let mut sum: u64 = energy[..window].iter().sum();
+    let (mut best_offset, mut best_sum) = (0, sum);
+    for offset in 1..=(energy.len() - window) {
+        sum = sum + energy[offset + window - 1] - energy[offset - 1];
+        let closer_to_centre = (offset as i64 - centre as i64).abs() < (best_offset as i64 - centre as i64).abs();
+        if sum > best_sum || (sum == best_sum && closer_to_centre) {
+            best_offset = offset;
+            best_sum = sum;
+        }
+    }
+
+    let offset = ((best_offset as u64 * length as u64 / analysis_length as u64) as u32).min(length - crop_length);
+    if horizontal {
+        (offset, 0)
+    } else {
+        (0, offset)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_crop_size() {
+        assert_eq!(crop_size(400, 300, 1, 1), (300, 300));
+        assert_eq!(crop_size(300, 400, 1, 1), (300, 300));
+        assert_eq!(crop_size(400, 300, 4, 3), (400, 300));
+        assert_eq!(crop_size(400, 300, 16, 9), (400, 225));
+        assert_eq!(crop_size(400, 300, 1, 1000), (1, 300));
+    }
+
+    #[test]
+    fn test_find() {
+        // flat image with a checkerboard on its right, the crop should move over to it
+        let image = image::RgbImage::from_fn(400, 100, |x, y| {
+            if x >= 300 && (x / 4 + y / 4) % 2 == 0 { image::Rgb([255, 255, 255]) } else { image::Rgb([0, 0, 0]) }
+        });
+        let (x, y) = find(&DynamicImage::ImageRgb8(image), 100, 100);
+        assert_eq!(y, 0);
+        assert!((290..=300).contains(&x), "crop should cover the checkerboard, but starts at {}", x);
+
+        // nothing interesting at all, centred
+        let image = DynamicImage::ImageRgb8(image::RgbImage::new(100, 400));
+        assert_eq!(find(&image, 100, 100), (0, 150));
+    }
+}
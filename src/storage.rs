@@ -0,0 +1,675 @@
+//! Storage of the WebP cache, either the directory layout under `webp_path` or an S3-compatible bucket
+
+use std::future::Future;
+use std::io::{self, Write};
+use std::path::{Component, Path, PathBuf};
+use std::pin::Pin;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use hyper::body::Bytes;
+use hyper::client::HttpConnector;
+use hyper::{Body, Client, Method, Request, Response, StatusCode, Uri};
+use serde::Deserialize;
+
+use crate::lru::LruCache;
+use crate::signature;
+
+pub type StorageFuture<'a, T> = Pin<Box<dyn Future<Output = Result<T, io::Error>> + Send + 'a>>;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Stat {
+    pub size: u64,
+}
+
+/// Cached files are addressed by their path under `webp_path`, wherever they are actually stored
+pub trait Storage: Send + Sync {
+    /// None if there is nothing at `path`
+    fn get<'a>(&'a self, path: &'a Path) -> StorageFuture<'a, Option<Bytes>>;
+    /// Replaces anything at `path`, readers never see partially written data
+    fn put<'a>(&'a self, path: &'a Path, data: &'a [u8]) -> StorageFuture<'a, ()>;
+    /// Deleting nothing is not an error
+    fn delete<'a>(&'a self, path: &'a Path) -> StorageFuture<'a, ()>;
+    /// Paths in the directory of `prefix` whose file names start with the file name of `prefix`
+    fn list<'a>(&'a self, prefix: &'a Path) -> StorageFuture<'a, Vec<PathBuf>>;
+    /// None if there is nothing at `path`
+    fn stat<'a>(&'a self, path: &'a Path) -> StorageFuture<'a, Option<Stat>>;
+}
+
+/// Writes to a temporary file first and renames it, so that a failed or interrupted write
+/// never leaves an empty or truncated file behind
+pub fn write_atomically(path: &Path, data: &[u8]) -> Result<(), io::Error> {
+    static TEMPORARY_FILE_COUNTER: std::sync::atomic::AtomicUsize = std::sync::atomic::AtomicUsize::new(0);
+    let mut temporary_path = path.as_os_str().to_owned();
+    temporary_path.push(format!(".{}.{}.tmp", std::process::id(),
+                                TEMPORARY_FILE_COUNTER.fetch_add(1, std::sync::atomic::Ordering::Relaxed)));
+    let result = std::fs::File::create(&temporary_path)
+        .and_then(|mut file| file.write_all(data))
+        .and_then(|_| std::fs::rename(&temporary_path, path));
+    if result.is_err() {
+        let _ = std::fs::remove_file(&temporary_path);
+    }
+    result
+}
+
+/// Reads the whole body, unless it's larger than `max_bytes`
+pub async fn read_body(mut body: Body, max_bytes: u64) -> Result<Vec<u8>, io::Error> {
+    use hyper::body::HttpBody;
+    let mut data = Vec::new();
+    while let Some(chunk) = body.data().await {
+        let chunk = chunk.map_err(io::Error::other)?;
+        if (data.len() + chunk.len()) as u64 > max_bytes {
+            return Err(io::Error::other(format!("response is larger than {} bytes", max_bytes)));
+        }
+        data.extend_from_slice(&chunk);
+    }
+    Ok(data)
+}
+
+/// `write_atomically` on a blocking thread, so that the runtime goes on meanwhile
+pub async fn write_atomically_in_background(path: &Path, data: Vec<u8>) -> Result<(), io::Error> {
+    let path = path.to_path_buf();
+    tokio::task::spawn_blocking(move || write_atomically(&path, &data)).await.map_err(io::Error::other)?
+}
+
+/// Files under `webp_path`, e.g. `/var/www/cache/path/to/aya.jpg.1582735380.webp`
+pub struct FilesystemStorage;
+
+impl Storage for FilesystemStorage {
+    fn get<'a>(&'a self, path: &'a Path) -> StorageFuture<'a, Option<Bytes>> {
+        Box::pin(async move {
+            match tokio::fs::read(path).await {
+                Ok(data) => Ok(Some(Bytes::from(data))),
+                Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(None),
+                Err(e) => Err(e),
+            }
+        })
+    }
+
+    fn put<'a>(&'a self, path: &'a Path, data: &'a [u8]) -> StorageFuture<'a, ()> {
+        Box::pin(async move {
+            if let Some(parent) = path.parent() {
+                tokio::fs::create_dir_all(parent).await?;
+            }
+            write_atomically_in_background(path, data.to_vec()).await
+        })
+    }
+
+    fn delete<'a>(&'a self, path: &'a Path) -> StorageFuture<'a, ()> {
+        Box::pin(async move {
+            match tokio::fs::remove_file(path).await {
+                Err(e) if e.kind() != io::ErrorKind::NotFound => Err(e),
+                _ => Ok(()),
+            }
+        })
+    }
+
+    fn list<'a>(&'a self, prefix: &'a Path) -> StorageFuture<'a, Vec<PathBuf>> {
+        Box::pin(async move {
+            let (directory, name_prefix) = match (prefix.parent(), prefix.file_name().and_then(|name| name.to_str())) {
+                (Some(directory), Some(name_prefix)) => (directory, name_prefix),
+                _ => return Ok(Vec::new()),
+            };
+            let (directory, name_prefix) = (directory.to_path_buf(), name_prefix.to_string());
+            tokio::task::spawn_blocking(move || {
+                let entries = match std::fs::read_dir(directory) {
+                    Ok(entries) => entries,
+                    Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(Vec::new()),
+                    Err(e) => return Err(e),
+                };
+                Ok(entries.filter_map(|entry| entry.ok())
+                    .filter(|entry| entry.file_name().to_str().is_some_and(|name| {
+                        // in-flight writes are left to their writers
+                        name.starts_with(&name_prefix) && !name.ends_with(".tmp")
+                    }))
+                    .map(|entry| entry.path())
+                    .collect())
+            }).await.map_err(io::Error::other)?
+        })
+    }
+
+    fn stat<'a>(&'a self, path: &'a Path) -> StorageFuture<'a, Option<Stat>> {
+        Box::pin(async move {
+            match tokio::fs::metadata(path).await {
+                Ok(metadata) if metadata.is_file() => Ok(Some(Stat { size: metadata.len() })),
+                Ok(_) => Ok(None),
+                Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(None),
+                Err(e) => Err(e),
+            }
+        })
+    }
+}
+
+#[derive(Deserialize, Debug, Clone)]
+#[serde(deny_unknown_fields)]
+pub struct S3Config {
+    // e.g. `http://127.0.0.1:9000`, buckets are addressed by path
+    pub endpoint: String,
+    pub bucket: String,
+    // default is us-east-1
+    pub region: Option<String>,
+    pub access_key: String,
+    pub secret_key: String,
+    // prepended to every key, so that a bucket can be shared with other data
+    pub prefix: Option<String>,
+    // largest object or listing that is read, default is 64 MiB
+    pub max_bytes: Option<u64>,
+}
+
+const S3_DEFAULT_MAX_BYTES: u64 = 64 << 20;
+
+/// What AWS Signature Version 4 signs of a request
+pub struct CanonicalRequest<'a> {
+    pub method: &'a str,
+    // URI encoded path
+    pub uri: &'a str,
+    // URI encoded query sorted by name
+    pub query: &'a str,
+    // lowercase names, sorted by name
+    pub headers: &'a [(&'a str, &'a str)],
+    pub payload_hash: &'a str,
+}
+
+impl S3Config {
+    pub fn validate(&self) -> Result<(), String> {
+        match self.endpoint.parse::<Uri>() {
+            Ok(uri) if uri.scheme_str() == Some("http") && uri.host().is_some() => (),
+            _ => return Err(format!("s3: endpoint {} must be an http:// URL", self.endpoint)),
+        }
+        if self.bucket.is_empty() || self.bucket.contains('/') {
+            return Err(format!("s3: invalid bucket \"{}\"", self.bucket));
+        }
+        if self.access_key.is_empty() || self.secret_key.is_empty() {
+            return Err("s3: access_key and secret_key cannot be empty".to_string());
+        }
+        if self.max_bytes == Some(0) {
+            return Err("s3: max_bytes must be positive".to_string());
+        }
+        Ok(())
+    }
+
+    fn region(&self) -> &str {
+        self.region.as_deref().unwrap_or("us-east-1")
+    }
+
+    fn max_bytes(&self) -> u64 {
+        self.max_bytes.unwrap_or(S3_DEFAULT_MAX_BYTES)
+    }
+
+    /// `Authorization` header of AWS Signature Version 4, `amz_date` is also sent as `x-amz-date`
+    pub fn authorization(&self, service: &str, request: &CanonicalRequest, amz_date: &str) -> String {
+        let date = &amz_date[..8];
+        let scope = format!("{}/{}/{}/aws4_request", date, self.region(), service);
+        let canonical_headers: String = request.headers.iter().map(|(name, value)| format!("{}:{}\n", name, value.trim())).collect();
+        let signed_headers = request.headers.iter().map(|(name, _)| *name).collect::<Vec<&str>>().join(";");
+        let canonical_request = format!("{}\n{}\n{}\n{}\n{}\n{}", request.method, request.uri, request.query,
+                                        canonical_headers, signed_headers, request.payload_hash);
+        let string_to_sign = format!("AWS4-HMAC-SHA256\n{}\n{}\n{}", amz_date, scope,
+                                     signature::hex(&signature::sha256(canonical_request.as_bytes())));
+
+        let mut signing_key = signature::hmac_sha256(format!("AWS4{}", self.secret_key).as_bytes(), date.as_bytes());
+        for part in &[self.region(), service, "aws4_request"] {
+            signing_key = signature::hmac_sha256(&signing_key, part.as_bytes());
+        }
+        format!("AWS4-HMAC-SHA256 Credential={}/{}, SignedHeaders={}, Signature={}", self.access_key, scope, signed_headers,
+                signature::hex(&signature::hmac_sha256(&signing_key, string_to_sign.as_bytes())))
+    }
+}
+
+/// Objects in an S3-compatible bucket, so that several servers can share one cache.
+/// `webp_path/path/to/aya.jpg.1582735380.webp` is stored as `prefix/path/to/aya.jpg.1582735380.webp`
+pub struct S3Storage {
+    config: S3Config,
+    root: PathBuf,
+    client: Client<HttpConnector>,
+}
+
+impl S3Storage {
+    pub fn new(config: S3Config, webp_path: &str) -> S3Storage {
+        S3Storage {
+            config,
+            root: PathBuf::from(webp_path),
+            client: Client::new(),
+        }
+    }
+
+    fn key_prefix(&self) -> String {
+        match self.config.prefix.as_deref().map(|prefix| prefix.trim_matches('/')) {
+            Some(prefix) if !prefix.is_empty() => format!("{}/", prefix),
+            _ => String::new(),
+        }
+    }
+
+    fn key(&self, path: &Path) -> Result<String, io::Error> {
+        let invalid_path = || io::Error::new(io::ErrorKind::InvalidInput, format!("{} is not a valid cache path", path.display()));
+        let relative_path = path.strip_prefix(&self.root).map_err(|_| invalid_path())?;
+        let names = relative_path.components().map(|component| match component {
+            Component::Normal(name) => name.to_str().ok_or_else(invalid_path),
+            _ => Err(invalid_path()),
+        }).collect::<Result<Vec<&str>, io::Error>>()?;
+        Ok(format!("{}{}", self.key_prefix(), names.join("/")))
+    }
+
+    fn path(&self, key: &str) -> PathBuf {
+        let relative_key = key.strip_prefix(&self.key_prefix()).unwrap_or(key);
+        relative_key.split('/').fold(self.root.clone(), |path, name| path.join(name))
+    }
+
+    async fn send(&self, method: Method, key: &str, query: &[(&str, &str)], body: Vec<u8>) -> Result<Response<Body>, io::Error> {
+        let endpoint = self.config.endpoint.parse::<Uri>().map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?;
+        let host = endpoint.authority().map(|authority| authority.as_str()).unwrap_or_default();
+        let uri = format!("{}/{}/{}", endpoint.path().trim_end_matches('/'), uri_encode(&self.config.bucket, true), uri_encode(key, false));
+        let mut query: Vec<(String, String)> = query.iter().map(|(name, value)| (uri_encode(name, true), uri_encode(value, true))).collect();
+        query.sort();
+        let query = query.iter().map(|(name, value)| format!("{}={}", name, value)).collect::<Vec<String>>().join("&");
+
+        let payload_hash = signature::hex(&signature::sha256(&body));
+        let amz_date = amz_date(SystemTime::now());
+        let headers = [("host", host), ("x-amz-content-sha256", payload_hash.as_str()), ("x-amz-date", amz_date.as_str())];
+        let authorization = self.config.authorization("s3", &CanonicalRequest {
+            method: method.as_str(),
+            uri: &uri,
+            query: &query,
+            headers: &headers,
+            payload_hash: &payload_hash,
+        }, &amz_date);
+
+        let mut request = Request::builder()
+            .method(method)
+            .uri(format!("http://{}{}{}{}", host, uri, if query.is_empty() { "" } else { "?" }, query));
+        for (name, value) in headers.iter() {
+            request = request.header(*name, *value);
+        }
+        let request = request.header(hyper::header::AUTHORIZATION, authorization).body(Body::from(body))
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?;
+        self.client.request(request).await.map_err(io::Error::other)
+    }
+}
+
+fn unexpected_status(operation: &str, key: &str, status: StatusCode) -> io::Error {
+    io::Error::other(format!("s3: {} {} responded {}", operation, key, status))
+}
+
+impl Storage for S3Storage {
+    fn get<'a>(&'a self, path: &'a Path) -> StorageFuture<'a, Option<Bytes>> {
+        Box::pin(async move {
+            let key = self.key(path)?;
+            let response = self.send(Method::GET, &key, &[], Vec::new()).await?;
+            match response.status() {
+                StatusCode::OK => Ok(Some(Bytes::from(read_body(response.into_body(), self.config.max_bytes()).await?))),
+                StatusCode::NOT_FOUND => Ok(None),
+                status => Err(unexpected_status("GET", &key, status)),
+            }
+        })
+    }
+
+    fn put<'a>(&'a self, path: &'a Path, data: &'a [u8]) -> StorageFuture<'a, ()> {
+        Box::pin(async move {
+            let key = self.key(path)?;
+            match self.send(Method::PUT, &key, &[], data.to_vec()).await?.status() {
+                StatusCode::OK => Ok(()),
+                status => Err(unexpected_status("PUT", &key, status)),
+            }
+        })
+    }
+
+    fn delete<'a>(&'a self, path: &'a Path) -> StorageFuture<'a, ()> {
+        Box::pin(async move {
+            let key = self.key(path)?;
+            match self.send(Method::DELETE, &key, &[], Vec::new()).await?.status() {
+                StatusCode::OK | StatusCode::NO_CONTENT | StatusCode::NOT_FOUND => Ok(()),
+                status => Err(unexpected_status("DELETE", &key, status)),
+            }
+        })
+    }
+
+    fn list<'a>(&'a self, prefix: &'a Path) -> StorageFuture<'a, Vec<PathBuf>> {
+        Box::pin(async move {
+            // objects in the same "directory" only, so what follows the prefix has no `/`
+            let key_prefix = self.key(prefix)?;
+            let mut paths = Vec::new();
+            let mut continuation_token: Option<String> = None;
+            loop {
+                let mut query = vec![("list-type", "2"), ("prefix", key_prefix.as_str())];
+                if let Some(continuation_token) = &continuation_token {
+                    query.push(("continuation-token", continuation_token));
+                }
+                let response = self.send(Method::GET, "", &query, Vec::new()).await?;
+                if response.status() != StatusCode::OK {
+                    return Err(unexpected_status("LIST", &key_prefix, response.status()));
+                }
+                let body = read_body(response.into_body(), self.config.max_bytes()).await?;
+                let xml = String::from_utf8_lossy(&body);
+                // keys that don't start with the prefix are skipped, whatever the bucket sends
+                paths.extend(xml_values(&xml, "Key").iter()
+                    .filter(|key| key.strip_prefix(key_prefix.as_str()).is_some_and(|name| !name.contains('/')))
+                    .map(|key| self.path(key)));
+                continuation_token = match xml_values(&xml, "IsTruncated").first().map(String::as_str) {
+                    Some("true") => xml_values(&xml, "NextContinuationToken").into_iter().next(),
+                    _ => None,
+                };
+                if continuation_token.is_none() {
+                    return Ok(paths);
+                }
+            }
+        })
+    }
+
+    fn stat<'a>(&'a self, path: &'a Path) -> StorageFuture<'a, Option<Stat>> {
+        Box::pin(async move {
+            let key = self.key(path)?;
+            let response = self.send(Method::HEAD, &key, &[], Vec::new()).await?;
+            match response.status() {
+                StatusCode::OK => {
+                    let size = response.headers().get(hyper::header::CONTENT_LENGTH)
+                        .and_then(|value| value.to_str().ok())
+                        .and_then(|value| value.parse().ok())
+                        .unwrap_or(0);
+                    Ok(Some(Stat { size }))
+                },
+                StatusCode::NOT_FOUND => Ok(None),
+                status => Err(unexpected_status("HEAD", &key, status)),
+            }
+        })
+    }
+}
+
+/// Keeps the most recently used files of another storage in memory, up to `capacity` bytes.
+/// Cache paths change with the modification time of the original image, and old ones are deleted
+/// through here, so entries never go stale
+pub struct HotCache {
+    inner: Arc<dyn Storage>,
+    entries: Mutex<LruCache<PathBuf, Bytes>>,
+    hits: AtomicU64,
+    misses: AtomicU64,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct HotCacheStats {
+    pub hits: u64,
+    pub misses: u64,
+    pub entries: usize,
+    // in bytes
+    pub size: usize,
+}
+
+impl HotCache {
+    pub fn new(inner: Arc<dyn Storage>, capacity: usize) -> HotCache {
+        HotCache {
+            inner,
+            entries: Mutex::new(LruCache::new(capacity)),
+            hits: AtomicU64::new(0),
+            misses: AtomicU64::new(0),
+        }
+    }
+
+    pub fn stats(&self) -> HotCacheStats {
+        let entries = self.entries.lock().unwrap();
+        HotCacheStats {
+            hits: self.hits.load(Ordering::Relaxed),
+            misses: self.misses.load(Ordering::Relaxed),
+            entries: entries.len(),
+            size: entries.size(),
+        }
+    }
+}
+
+impl Storage for HotCache {
+    fn get<'a>(&'a self, path: &'a Path) -> StorageFuture<'a, Option<Bytes>> {
+        Box::pin(async move {
+            let path_buf = path.to_path_buf();
+            if let Some(data) = self.entries.lock().unwrap().get(&path_buf).cloned() {
+                self.hits.fetch_add(1, Ordering::Relaxed);
+                return Ok(Some(data));
+            }
+            self.misses.fetch_add(1, Ordering::Relaxed);
+            let data = self.inner.get(path).await?;
+            if let Some(data) = &data {
+                self.entries.lock().unwrap().insert(path_buf, data.clone());
+            }
+            Ok(data)
+        })
+    }
+
+    fn put<'a>(&'a self, path: &'a Path, data: &'a [u8]) -> StorageFuture<'a, ()> {
+        Box::pin(async move {
+            self.inner.put(path, data).await?;
+            self.entries.lock().unwrap().insert(path.to_path_buf(), Bytes::copy_from_slice(data));
+            Ok(())
+        })
+    }
+
+    fn delete<'a>(&'a self, path: &'a Path) -> StorageFuture<'a, ()> {
+        Box::pin(async move {
+            self.entries.lock().unwrap().remove(&path.to_path_buf());
+            self.inner.delete(path).await
+        })
+    }
+
+    fn list<'a>(&'a self, prefix: &'a Path) -> StorageFuture<'a, Vec<PathBuf>> {
+        self.inner.list(prefix)
+    }
+
+    fn stat<'a>(&'a self, path: &'a Path) -> StorageFuture<'a, Option<Stat>> {
+        self.inner.stat(path)
+    }
+}
+
+/// Percent-encodes everything but unreserved characters, and `/` unless `encode_slash`
+fn uri_encode(value: &str, encode_slash: bool) -> String {
+    value.bytes().map(|byte| match byte {
+        b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'_' | b'.' | b'~' => (byte as char).to_string(),
+        b'/' if !encode_slash => "/".to_string(),
+        _ => format!("%{:02X}", byte),
+    }).collect()
+}
+
+/// `20130524T000000Z`
+fn amz_date(time: SystemTime) -> String {
+    let seconds = time.duration_since(UNIX_EPOCH).map(|duration| duration.as_secs()).unwrap_or(0);
+    let (days, seconds) = ((seconds / 86400) as i64, seconds % 86400);
+    // civil date from days since 1970-01-01, see http://howardhinnant.github.io/date_algorithms.html
+    let days = days + 719468;
+    let era = days / 146097;
+    let day_of_era = days - era * 146097;
+    let year_of_era = (day_of_era - day_of_era / 1460 + day_of_era / 36524 - day_of_era / 146096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let shifted_month = (5 * day_of_year + 2) / 153;
+    let day = day_of_year - (153 * shifted_month + 2) / 5 + 1;
+    let month = if shifted_month < 10 { shifted_month + 3 } else { shifted_month - 9 };
+    let year = year_of_era + era * 400 + if month <= 2 { 1 } else { 0 };
+    format!("{:04}{:02}{:02}T{:02}{:02}{:02}Z", year, month, day, seconds / 3600, seconds % 3600 / 60, seconds % 60)
+}
+
+/// Text of every `<tag>` element, good enough for the flat responses of ListObjectsV2
+fn xml_values(xml: &str, tag: &str) -> Vec<String> {
+    let (open, close) = (format!("<{}>", tag), format!("</{}>", tag));
+    xml.split(open.as_str()).skip(1)
+        .filter_map(|rest| rest.split(close.as_str()).next())
+        .map(|value| value.replace("&lt;", "<").replace("&gt;", ">").replace("&quot;", "\"").replace("&apos;", "'").replace("&amp;", "&"))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::BTreeMap;
+    use std::sync::{Arc, Mutex};
+
+    #[test]
+    fn test_authorization() {
+        // get-vanilla from the AWS Signature Version 4 test suite
+        let config = S3Config {
+            endpoint: "http://example.amazonaws.com".to_string(),
+            bucket: "bucket".to_string(),
+            region: None,
+            access_key: "AKIDEXAMPLE".to_string(),
+            secret_key: "wJalrXUtnFEMI/K7MDENG+bPxRfiCYEXAMPLEKEY".to_string(),
+            prefix: None,
+            max_bytes: None,
+        };
+        let request = CanonicalRequest {
+            method: "GET",
+            uri: "/",
+            query: "",
+            headers: &[("host", "example.amazonaws.com"), ("x-amz-date", "20150830T123600Z")],
+            payload_hash: "e3b0c44298fc1c149afbf4c8996fb92427ae41e4649b934ca495991b7852b855",
+        };
+        assert_eq!(config.authorization("service", &request, "20150830T123600Z"),
+                   "AWS4-HMAC-SHA256 Credential=AKIDEXAMPLE/20150830/us-east-1/service/aws4_request, SignedHeaders=host;x-amz-date, \
+                    Signature=5fa00fa31553b73ebf1942676e86291e8372ff2a2260956d9b8aae1d763fbf31");
+        assert!(config.validate().is_ok());
+        assert!(S3Config { endpoint: "https://example.amazonaws.com".to_string(), ..config.clone() }.validate().is_err());
+        assert!(S3Config { bucket: "a/b".to_string(), ..config.clone() }.validate().is_err());
+        assert!(S3Config { max_bytes: Some(0), ..config }.validate().is_err());
+    }
+
+    #[test]
+    fn test_helpers() {
+        assert_eq!(amz_date(UNIX_EPOCH), "19700101T000000Z");
+        assert_eq!(amz_date(UNIX_EPOCH + std::time::Duration::from_secs(1369353600)), "20130524T000000Z");
+        assert_eq!(amz_date(UNIX_EPOCH + std::time::Duration::from_secs(951825599)), "20000229T115959Z");
+        assert_eq!(uri_encode("path/to/a b+c.jpg", false), "path/to/a%20b%2Bc.jpg");
+        assert_eq!(uri_encode("a/b", true), "a%2Fb");
+        assert_eq!(xml_values("<R><Key>a&amp;b</Key><Key>c</Key></R>", "Key"), vec!["a&b", "c"]);
+    }
+
+    #[tokio::test]
+    async fn test_filesystem_storage() {
+        let directory = PathBuf::from("./cache/test_filesystem_storage");
+        let _ = std::fs::remove_dir_all(&directory);
+        let storage = FilesystemStorage;
+        let path = directory.join("to/aya.jpg.1582735380.webp");
+        assert_eq!(storage.get(&path).await.unwrap(), None);
+        assert_eq!(storage.list(&directory.join("to/aya.jpg.")).await.unwrap(), Vec::<PathBuf>::new());
+
+        storage.put(&path, b"webp").await.unwrap();
+        std::fs::write(directory.join("to/aya.jpg.1582735380.webp.1.2.tmp"), b"").unwrap();
+        assert_eq!(storage.get(&path).await.unwrap(), Some(Bytes::from_static(b"webp")));
+        assert_eq!(storage.stat(&path).await.unwrap(), Some(Stat { size: 4 }));
+        assert_eq!(storage.list(&directory.join("to/aya.jpg.")).await.unwrap(), vec![path.clone()]);
+        storage.delete(&path).await.unwrap();
+        storage.delete(&path).await.unwrap();
+        assert_eq!(storage.stat(&path).await.unwrap(), None);
+        let _ = std::fs::remove_dir_all(&directory);
+    }
+
+    #[tokio::test]
+    async fn test_hot_cache() {
+        let directory = PathBuf::from("./cache/test_hot_cache");
+        let _ = std::fs::remove_dir_all(&directory);
+        let hot_cache = HotCache::new(Arc::new(FilesystemStorage), 8);
+        let (old_path, path) = (directory.join("aya.jpg.1582735300.webp"), directory.join("aya.jpg.1582735380.webp"));
+
+        assert_eq!(hot_cache.get(&path).await.unwrap(), None);
+        hot_cache.put(&old_path, b"old").await.unwrap();
+        FilesystemStorage.put(&path, b"webp").await.unwrap();
+        // read through once, then served from memory even if the file is gone
+        assert_eq!(hot_cache.get(&path).await.unwrap(), Some(Bytes::from_static(b"webp")));
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(hot_cache.get(&path).await.unwrap(), Some(Bytes::from_static(b"webp")));
+        assert_eq!(hot_cache.get(&old_path).await.unwrap(), Some(Bytes::from_static(b"old")));
+        assert_eq!(hot_cache.stats(), HotCacheStats { hits: 2, misses: 2, entries: 2, size: 7 });
+
+        hot_cache.delete(&old_path).await.unwrap();
+        assert_eq!(hot_cache.get(&old_path).await.unwrap(), None);
+        assert!(!old_path.exists());
+        // over capacity, the least recently used entry goes
+        hot_cache.put(&directory.join("aya.png.1582735380.webp"), b"png!").await.unwrap();
+        hot_cache.put(&directory.join("aya.gif.1582735380.webp"), b"gif").await.unwrap();
+        assert_eq!(hot_cache.get(&path).await.unwrap(), None);
+        assert_eq!(hot_cache.stats().entries, 2);
+        let _ = std::fs::remove_dir_all(&directory);
+    }
+
+    #[tokio::test]
+    async fn test_s3_storage() {
+        use hyper::service::{make_service_fn, service_fn};
+
+        // stand-in for an S3-compatible store, checks that requests are signed and hashed
+        let objects = Arc::new(Mutex::new(BTreeMap::<String, Vec<u8>>::new()));
+        let objects_copy = objects.clone();
+        let server = hyper::Server::bind(&"127.0.0.1:0".parse().unwrap()).serve(make_service_fn(move |_| {
+            let objects = objects_copy.clone();
+            async move {
+                Ok::<_, hyper::Error>(service_fn(move |req: Request<Body>| {
+                    let objects = objects.clone();
+                    async move {
+                        let authorization = req.headers()[hyper::header::AUTHORIZATION].to_str().unwrap().to_string();
+                        assert!(authorization.starts_with("AWS4-HMAC-SHA256 Credential=minio/"), "{}", authorization);
+                        assert!(authorization.contains("/eu-west-1/s3/aws4_request, SignedHeaders=host;x-amz-content-sha256;x-amz-date, Signature="));
+                        let payload_hash = req.headers()["x-amz-content-sha256"].to_str().unwrap().to_string();
+                        let (method, path, query) = (req.method().clone(), req.uri().path().to_string(), req.uri().query().unwrap_or_default().to_string());
+                        let body = hyper::body::to_bytes(req.into_body()).await.unwrap().to_vec();
+                        assert_eq!(payload_hash, signature::hex(&signature::sha256(&body)));
+
+                        let mut objects = objects.lock().unwrap();
+                        let key = path.strip_prefix("/cache/").unwrap().to_string();
+                        let response = Response::builder();
+                        Ok::<_, hyper::Error>(match (method, objects.get(&key).cloned()) {
+                            (Method::GET, _) if key.is_empty() => {
+                                let prefix = query.split('&').find_map(|pair| pair.strip_prefix("prefix=")).unwrap().replace("%2F", "/");
+                                let mut keys: String = objects.keys().filter(|key| key.starts_with(&prefix)).map(|key| format!("<Key>{}</Key>", key)).collect();
+                                // as if the prefix was ignored, shorter than it and not ASCII where it ends
+                                keys.push_str("<Key>webp</Key><Key>webp/path/ööö</Key>");
+                                response.body(Body::from(format!("<ListBucketResult><IsTruncated>false</IsTruncated>{}</ListBucketResult>", keys)))
+                            },
+                            (Method::GET, Some(data)) => response.body(Body::from(data)),
+                            (Method::HEAD, Some(data)) => response.header(hyper::header::CONTENT_LENGTH, data.len()).body(Body::empty()),
+                            (Method::PUT, _) => {
+                                objects.insert(key, body);
+                                response.body(Body::empty())
+                            },
+                            (Method::DELETE, _) => {
+                                objects.remove(&key);
+                                response.status(StatusCode::NO_CONTENT).body(Body::empty())
+                            },
+                            _ => response.status(StatusCode::NOT_FOUND).body(Body::empty()),
+                        }.unwrap())
+                    }
+                }))
+            }
+        }));
+        let config = S3Config {
+            endpoint: format!("http://{}", server.local_addr()),
+            bucket: "cache".to_string(),
+            region: Some("eu-west-1".to_string()),
+            access_key: "minio".to_string(),
+            secret_key: "minio123".to_string(),
+            prefix: Some("webp/".to_string()),
+            max_bytes: Some(4),
+        };
+        assert!(config.validate().is_ok());
+        tokio::spawn(server);
+
+        let storage = S3Storage::new(config.clone(), "./cache");
+        let path = Path::new("./cache/path/to/aya.jpg.1582735380.webp");
+        assert_eq!(storage.key(path).unwrap(), "webp/path/to/aya.jpg.1582735380.webp");
+        assert_eq!(storage.path("webp/path/to/aya.jpg.1582735380.webp"), PathBuf::from("./cache/path/to/aya.jpg.1582735380.webp"));
+        assert!(storage.key(Path::new("./images/aya.jpg")).is_err());
+        assert!(storage.key(Path::new("./cache/../aya.jpg")).is_err());
+
+        assert_eq!(storage.get(path).await.unwrap(), None);
+        assert_eq!(storage.stat(path).await.unwrap(), None);
+        storage.put(path, b"webp").await.unwrap();
+        storage.put(Path::new("./cache/path/to/aya.jpg.1582735380.lqip.webp"), b"lqip").await.unwrap();
+        storage.put(Path::new("./cache/path/to/aya.jpg.d/aya.jpg.1582735380.webp"), b"nested").await.unwrap();
+        assert_eq!(storage.get(path).await.unwrap(), Some(Bytes::from_static(b"webp")));
+        assert_eq!(storage.stat(path).await.unwrap(), Some(Stat { size: 4 }));
+        // larger objects and listings are not read at all
+        storage.put(path, b"webp!").await.unwrap();
+        assert!(storage.get(path).await.is_err());
+        assert!(storage.list(Path::new("./cache/path/to/aya.jpg.")).await.is_err());
+        storage.put(path, b"webp").await.unwrap();
+        let storage = S3Storage::new(S3Config { max_bytes: None, ..config }, "./cache");
+        assert_eq!(storage.list(Path::new("./cache/path/to/aya.jpg.")).await.unwrap(), vec![
+            PathBuf::from("./cache/path/to/aya.jpg.1582735380.lqip.webp"),
+            PathBuf::from("./cache/path/to/aya.jpg.1582735380.webp"),
+        ]);
+        storage.delete(path).await.unwrap();
+        storage.delete(path).await.unwrap();
+        assert_eq!(storage.get(path).await.unwrap(), None);
+        assert_eq!(objects.lock().unwrap().len(), 2);
+    }
+}
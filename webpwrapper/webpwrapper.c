@@ -8,6 +8,7 @@
 
 #include <stdlib.h>
 #include <string.h>
+#include <webp/decode.h>
 #include <webp/encode.h>
 
 typedef int (*Importer)(WebPPicture* const, const uint8_t* const, int);
@@ -57,6 +58,12 @@ SET_WEBP_CONFIG_PARAM_INT(near_lossless)
 SET_WEBP_CONFIG_PARAM_INT(exact)
 SET_WEBP_CONFIG_PARAM_INT(use_delta_palette)
 SET_WEBP_CONFIG_PARAM_INT(use_sharp_yuv)
+SET_WEBP_CONFIG_PARAM_INT(qmin)
+SET_WEBP_CONFIG_PARAM_INT(qmax)
+
+int webp_validate_config(const WebPConfig * config) {
+  return WebPValidateConfig(config);
+}
 
 #define WEBP_HINT_DEFAULT_TYPE  1
 #define WEBP_HINT_PICTURE_TYPE  2
@@ -116,25 +123,38 @@ void set_webp_config_preset(WebPConfig * config, int value, float quality_factor
   WebPConfigPreset(config, preset, quality_factor);
 }
 
+int set_webp_config_lossless_preset(WebPConfig * config, int level) {
+  return WebPConfigLosslessPreset(config, level);
+}
+
 #define WEBP_PICTURE_IMPORT_RGB_TYPE  1
 #define WEBP_PICTURE_IMPORT_RGBA_TYPE 2
 #define WEBP_PICTURE_IMPORT_BGR_TYPE  3
 #define WEBP_PICTURE_IMPORT_BGRA_TYPE 4
 
-size_t webp_encoder(const uint8_t* rgba, int width, int height, int stride,
-                    int importer_type,
-                    WebPConfig * config,
-                    uint8_t** output) {
+#define WEBPWRAPPER_ERROR_INVALID_IMPORTER -1
+#define WEBPWRAPPER_ERROR_PICTURE_INIT     -2
+
+// returns 0 on success, otherwise a WebPEncodingError or one of WEBPWRAPPER_ERROR_*
+// the encoded data in *output must be freed with webp_free()
+int webp_encoder(const uint8_t* rgba, int width, int height, int stride,
+                 int importer_type,
+                 WebPConfig * config,
+                 uint8_t** output,
+                 size_t* output_size) {
   WebPPicture pic;
   WebPMemoryWriter wrt;
   int ok;
 
-  if (output == NULL) return 0;
-  if (!WebPPictureInit(&pic)) return 0;
+  if (output == NULL || output_size == NULL) return VP8_ENC_ERROR_NULL_PARAMETER;
+  *output = NULL;
+  *output_size = 0;
+  if (rgba == NULL || config == NULL) return VP8_ENC_ERROR_NULL_PARAMETER;
+  if (!WebPPictureInit(&pic)) return WEBPWRAPPER_ERROR_PICTURE_INIT;
 
   Importer import = 0;
   switch (importer_type) {
-  	case WEBP_PICTURE_IMPORT_RGB_TYPE:
+    case WEBP_PICTURE_IMPORT_RGB_TYPE:
       import = WebPPictureImportRGB;
       break;
     case WEBP_PICTURE_IMPORT_RGBA_TYPE:
@@ -146,13 +166,10 @@ size_t webp_encoder(const uint8_t* rgba, int width, int height, int stride,
     case WEBP_PICTURE_IMPORT_BGRA_TYPE:
       import = WebPPictureImportBGRA;
       break;
-    default: {
-      *output = NULL;
-      return 0;
-      break;
-    }
+    default:
+      return WEBPWRAPPER_ERROR_INVALID_IMPORTER;
   }
-  
+
   pic.use_argb = !!config->lossless;
   pic.width = width;
   pic.height = height;
@@ -161,12 +178,22 @@ size_t webp_encoder(const uint8_t* rgba, int width, int height, int stride,
   WebPMemoryWriterInit(&wrt);
 
   ok = import(&pic, rgba, stride) && WebPEncode(config, &pic);
-  WebPPictureFree(&pic);
   if (!ok) {
+    int error_code = pic.error_code != VP8_ENC_OK ? pic.error_code : VP8_ENC_ERROR_OUT_OF_MEMORY;
+    WebPPictureFree(&pic);
     WebPMemoryWriterClear(&wrt);
-    *output = NULL;
-    return 0;
+    return error_code;
   }
+  WebPPictureFree(&pic);
   *output = wrt.mem;
-  return wrt.size;
+  *output_size = wrt.size;
+  return VP8_ENC_OK;
+}
+
+uint8_t * webp_decoder(const uint8_t* data, size_t data_size, int* width, int* height) {
+  return WebPDecodeRGBA(data, data_size, width, height);
+}
+
+void webp_free(void * ptr) {
+  WebPFree(ptr);
 }